@@ -1,17 +1,85 @@
+use std::env;
+use std::io;
+
 use server_v2::resources::*;
+use server_v2::util::{backup, health, logger, metrics, migration};
+use server_v2::util::access_log::AccessLog;
+use server_v2::util::security::Security;
+use server_v2::util::database::Database;
+use server_v2::util::state::{AppState, connect_database};
 
-#[actix_rt::main]
-async fn main() -> std::io::Result<()> {
-    use actix_web::{App, HttpServer};
-    HttpServer::new(|| {
+// 收到 SIGINT/SIGTERM 后最多等待进行中的请求这么久
+const SHUTDOWN_TIMEOUT: u64 = 30;
+
+async fn serve() -> std::io::Result<()> {
+    use actix_web::{App, HttpServer, web};
+    let state = match AppState::new().await {
+        Ok(state) => web::Data::new(state),
+        Err(err) => {
+            log::error!("startup failed: {}", err);
+            return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+        }
+    };
+    let security = state.policies.security.clone();
+    let report = &state.db.index_report;
+    for index in &report.created {
+        log::info!("created index {}", index);
+    }
+    for conflict in &report.conflicting {
+        log::warn!("index conflict: {}", conflict);
+    }
+    match session::restore_sessions(&state.db).await {
+        Ok(count) => log::info!("restored {} sessions", count),
+        Err(err) => log::warn!("restore sessions failed: {}", err),
+    }
+    email_queue::spawn_worker(state.clone());
+    let app_state = state.clone();
+    HttpServer::new(move || {
         App::new()
-            .configure(course::config)
-            .configure(rate::config)
-            .configure(session::config)
-            .configure(comment::config)
-            .configure(detail::config)
+            .app_data(app_state.clone())
+            .app_data(security.json_config())
+            .wrap(Security::new(security.clone()))
+            .wrap(AccessLog)
+            .configure(server_v2::resources::config)
+            .configure(metrics::config)
+            .configure(health::config)
     })
+        .shutdown_timeout(SHUTDOWN_TIMEOUT)
         .bind("127.0.0.1:8088")?
         .run()
-        .await
-}
\ No newline at end of file
+        .await?;
+
+    log::info!("server stopped, flushing email queue");
+    if let Err(err) = email_queue::process_due(&state.db, &state.email_sender).await {
+        log::error!("flush email queue failed: {}", err);
+    }
+    match session::persist_sessions(&state.db).await {
+        Ok(count) => log::info!("persisted {} sessions", count),
+        Err(err) => log::error!("persist sessions failed: {}", err),
+    }
+    Ok(())
+}
+
+async fn database() -> io::Result<Database> {
+    connect_database().await.map_err(|err| {
+        log::error!("startup failed: {}", err);
+        io::Error::new(io::ErrorKind::Other, err.to_string())
+    })
+}
+
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    // 日志还没初始化，配置错误只能直接打到 stderr
+    let log_config = logger::load().await.map_err(|err| {
+        eprintln!("startup failed: invalid {}: {}", logger::LOG_CONFIG, err);
+        io::Error::new(io::ErrorKind::Other, err.to_string())
+    })?;
+    logger::init(&log_config);
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    match args.first().map(String::as_str) {
+        Some("import") => import::run_command(&database().await?, &args[1..]).await,
+        Some("migrate") => migration::run_command(&database().await?, &args[1..]).await,
+        Some(command @ "export") | Some(command @ "restore") => backup::run_command(&database().await?, command, &args[1..]).await,
+        _ => serve().await,
+    }
+}
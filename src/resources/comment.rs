@@ -1,18 +1,78 @@
+use std::cmp::Ordering;
 use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
 use actix_web::{Responder, web};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use futures::future;
-use futures::stream::StreamExt;
-use mongodb::bson::{Bson, doc, Document, from_bson, to_bson};
-use mongodb::options::ReplaceOptions;
+use chrono::{Datelike, DateTime, FixedOffset, NaiveDate, Utc};
+use lazy_static::lazy_static;
+use mongodb::bson::{self, Bson, doc, Document, from_bson, to_bson};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::json_response;
+use crate::resources::detail::Offering;
 use crate::resources::session::get_session;
-use crate::util::database::Database;
-use crate::util::database::DEFAULT_DATABASE;
+use crate::util::config::CommentConfig;
+use crate::util::index::IndexSpec;
+use crate::util::metrics::timed;
 use crate::util::ops::PatchOperator;
+use crate::util::repository::{CommentRepository, Repositories};
+use crate::util::state::AppState;
+
+const DEFAULT_MIN_LENGTH: usize = 1;
+const DEFAULT_MAX_LENGTH: usize = 2000;
+const DEFAULT_RATING_MIN: f32 = 0.0;
+const DEFAULT_RATING_MAX: f32 = 5.0;
+const DEFAULT_EARLIEST_YEAR: i32 = 2012;
+// 评论日期按北京时间（Asia/Shanghai，无夏令时）判断是否晚于今天
+const LOCAL_OFFSET_SECS: i32 = 8 * 3600;
+const RATE_FIELDS: [&str; 4] = ["likes", "useful", "easy", "ratings"];
+// 可以单独修改的字段，rate.<RATE_FIELDS> 另外判断
+const PATCHABLE_FIELDS: [&str; 6] = ["content", "rate", "gpa", "willing", "anonymous", "taught"];
+// 这些字段要一起校验，修改时需要重新提交整条评论；时间戳由服务端维护
+const READ_ONLY_FIELDS: [&str; 8] = ["cid", "comment_by", "term", "year", "month", "day", "created_at", "updated_at"];
+
+lazy_static! {
+    // script / style 连同内容一起去掉，其余标签只去掉标签本身，保留文字
+    static ref SCRIPT_BLOCK: Regex = Regex::new(r"(?is)<(script|style)\b.*?(</(script|style)\s*>|$)").unwrap();
+    static ref HTML_TAG: Regex = Regex::new(r"(?s)<[A-Za-z/!?][^>]*(>|$)").unwrap();
+}
+
+#[derive(Debug)]
+pub enum CommentError {
+    ContentLength(usize, usize),
+    RatingRange(String, f32, f32),
+    InvalidDate,
+    YearRange(i32, i32),
+    CourseNotFound,
+    NotOffered,
+    ReadOnly(String),
+    NotPatchable(String),
+    InvalidPatch(String),
+    UnknownSort(String),
+}
+
+impl fmt::Display for CommentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommentError::ContentLength(min, max) => write!(f, "comment content must be {} to {} characters", min, max),
+            CommentError::RatingRange(field, min, max) => write!(f, "rate.{} must be between {} and {}", field, min, max),
+            CommentError::InvalidDate => write!(f, "year, month and day must be a past calendar date"),
+            CommentError::YearRange(earliest, latest) => write!(f, "year must be between {} and {}", earliest, latest),
+            CommentError::CourseNotFound => write!(f, "course not found"),
+            CommentError::NotOffered => write!(f, "course was not offered in that year and term"),
+            CommentError::ReadOnly(field) => write!(f, "{} cannot be patched, post the comment again instead", field),
+            CommentError::NotPatchable(field) => write!(f, "{} cannot be patched", field),
+            CommentError::InvalidPatch(field) => write!(f, "{} can only be set to a valid value", field),
+            CommentError::UnknownSort(sort) => write!(f, "unknown sort {}, expected newest, helpful or rating", sort),
+        }
+    }
+}
+
+impl Error for CommentError {}
 
 #[derive(Debug, Deserialize, Serialize)]
 enum Gpa {
@@ -41,8 +101,8 @@ enum Gpa {
     X,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-enum Term {
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+pub(crate) enum Term {
     #[serde(rename(serialize = "春", deserialize = "春"))]
     Spring,
     #[serde(rename(serialize = "夏", deserialize = "夏"))]
@@ -72,45 +132,215 @@ pub struct Comment {
     anonymous: bool,
     rate: Rate,
     taught: Vec<String>,
-    helpful: Option<usize>,
-    not_helpful: Option<usize>,
-    year: usize,
-    month: usize,
-    day: usize,
+    helpful: Option<i64>,
+    not_helpful: Option<i64>,
+    year: i32,
+    month: i32,
+    day: i32,
+    created_at: Option<bson::DateTime>,
+    updated_at: Option<bson::DateTime>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommentSort {
+    Newest,
+    Helpful,
+    Rating,
 }
 
-async fn delete_comment(db: Option<&Database>, filter: Option<Document>, comment_by: &str) -> Result<i64, Box<dyn Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
+impl FromStr for CommentSort {
+    type Err = CommentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newest" => Ok(CommentSort::Newest),
+            "helpful" => Ok(CommentSort::Helpful),
+            "rating" => Ok(CommentSort::Rating),
+            _ => Err(CommentError::UnknownSort(s.to_string())),
+        }
+    }
+}
+
+impl CommentSort {
+    // 均为降序，相同时新的在前；没有时间戳的旧评论排在最后
+    fn sort(&self, comments: &mut [Comment]) {
+        let newest = |a: &Comment, b: &Comment| b.created_at.cmp(&a.created_at);
+        match self {
+            CommentSort::Newest => comments.sort_by(newest),
+            CommentSort::Helpful => comments.sort_by(|a, b| b.helpful.cmp(&a.helpful).then_with(|| newest(a, b))),
+            CommentSort::Rating => comments.sort_by(|a, b| {
+                b.rate.ratings.partial_cmp(&a.rate.ratings).unwrap_or(Ordering::Equal).then_with(|| newest(a, b))
+            }),
+        }
+    }
+}
+
+fn strip_tags(content: &str) -> String {
+    let content = SCRIPT_BLOCK.replace_all(content, "");
+    HTML_TAG.replace_all(&content, "").trim().to_string()
+}
+
+fn escape_html(content: &str) -> String {
+    content.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// &amp; 最后还原，避免 &amp;lt; 被还原两次
+fn unescape_html(content: &str) -> String {
+    content.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+// 客户端会把读到的转义正文原样提交回来，先还原再处理；去标签后剩下的文字可能重新拼成标签，去到不再变化为止
+fn plain_text(content: &str) -> String {
+    let mut text = unescape_html(content);
+    loop {
+        let stripped = strip_tags(&text);
+        if stripped == text {
+            return text;
+        }
+        text = stripped;
+    }
+}
+
+// 结果可以重复清洗：sanitize_content(sanitize_content(x)) == sanitize_content(x)
+pub fn sanitize_content(content: &str) -> String {
+    escape_html(&plain_text(content))
+}
+
+// 长度按去标签后、转义前的字符数计算
+fn check_content(content: &str, config: &CommentConfig) -> Result<String, CommentError> {
+    let min = config.min_length.unwrap_or(DEFAULT_MIN_LENGTH);
+    let max = config.max_length.unwrap_or(DEFAULT_MAX_LENGTH);
+    let content = plain_text(content);
+    let length = content.chars().count();
+    if length < min || length > max {
+        Err(CommentError::ContentLength(min, max))
+    } else {
+        Ok(escape_html(&content))
+    }
+}
+
+fn check_rating(field: &str, value: f32, config: &CommentConfig) -> Result<(), CommentError> {
+    let min = config.rating_min.unwrap_or(DEFAULT_RATING_MIN);
+    let max = config.rating_max.unwrap_or(DEFAULT_RATING_MAX);
+    if value.is_finite() && value >= min && value <= max {
+        Ok(())
+    } else {
+        Err(CommentError::RatingRange(field.to_string(), min, max))
+    }
+}
+
+impl Rate {
+    fn validate(&self, config: &CommentConfig) -> Result<(), CommentError> {
+        let values = [self.likes, self.useful, self.easy, self.ratings];
+        RATE_FIELDS.iter().zip(values.iter()).try_for_each(|(field, value)| check_rating(field, *value, config))
+    }
+}
+
+fn local_date(now: DateTime<Utc>) -> NaiveDate {
+    now.with_timezone(&FixedOffset::east(LOCAL_OFFSET_SECS)).date().naive_local()
+}
+
+impl Comment {
+    // 通过校验时返回清洗过正文的评论
+    pub(crate) fn validate(mut self, config: &CommentConfig, today: NaiveDate) -> Result<Comment, CommentError> {
+        self.content = check_content(&self.content, config)?;
+        self.rate.validate(config)?;
+        let earliest = config.earliest_year.unwrap_or(DEFAULT_EARLIEST_YEAR);
+        if self.year < earliest || self.year > today.year() {
+            return Err(CommentError::YearRange(earliest, today.year()));
+        }
+        if self.month < 1 || self.day < 1 {
+            return Err(CommentError::InvalidDate);
+        }
+        match NaiveDate::from_ymd_opt(self.year, self.month as u32, self.day as u32) {
+            Some(date) if date <= today => Ok(self),
+            _ => Err(CommentError::InvalidDate),
+        }
+    }
+}
+
+fn number(value: &Bson) -> Option<f32> {
+    match value {
+        Bson::Double(f) => Some(*f as f32),
+        Bson::Int32(i) => Some(*i as f32),
+        Bson::Int64(i) => Some(*i as f32),
+        _ => None,
+    }
+}
+
+fn is_patchable(field: &str) -> bool {
+    PATCHABLE_FIELDS.contains(&field) || (field.starts_with("rate.") && RATE_FIELDS.contains(&&field[5..]))
+}
+
+// 只允许修改 PATCHABLE_FIELDS，值的类型和范围与提交时一致；helpful 等计数不能由作者修改
+fn check_patch(op: PatchOperator, config: &CommentConfig) -> Result<PatchOperator, Box<dyn Error>> {
+    let field = match &op {
+        PatchOperator::AddToSet(field, _) | PatchOperator::Set(field, _)
+        | PatchOperator::Inc(field, _) | PatchOperator::RmFromSet(field, _) => field.clone(),
+    };
+    if READ_ONLY_FIELDS.contains(&field.as_str()) {
+        return Err(Box::new(CommentError::ReadOnly(field)));
+    }
+    if !is_patchable(&field) {
+        return Err(Box::new(CommentError::NotPatchable(field)));
+    }
+    let invalid = || CommentError::InvalidPatch(field.clone());
+    let value = match op {
+        PatchOperator::Set(_, Bson::String(content)) if field == "content" => Bson::String(check_content(&content, config)?),
+        PatchOperator::Set(_, value) if field == "rate" => {
+            let rate = from_bson::<Rate>(value).map_err(|_| invalid())?;
+            rate.validate(config)?;
+            to_bson(&rate)?
+        }
+        PatchOperator::Set(_, value) if field.starts_with("rate.") => {
+            check_rating(&field[5..], number(&value).ok_or_else(invalid)?, config)?;
+            value
+        }
+        PatchOperator::Set(_, value) if field == "gpa" => {
+            if value != Bson::Null {
+                from_bson::<Gpa>(value.clone()).map_err(|_| invalid())?;
+            }
+            value
+        }
+        PatchOperator::Set(_, Bson::Boolean(value)) if field == "willing" || field == "anonymous" => Bson::Boolean(value),
+        PatchOperator::Set(_, Bson::Array(teachers)) if field == "taught" && teachers.iter().all(|t| t.as_str().is_some()) => {
+            Bson::Array(teachers)
+        }
+        PatchOperator::AddToSet(_, Bson::String(teacher)) if field == "taught" => {
+            return Ok(PatchOperator::AddToSet(field, Bson::String(teacher)));
+        }
+        PatchOperator::RmFromSet(_, Bson::String(teacher)) if field == "taught" => {
+            return Ok(PatchOperator::RmFromSet(field, Bson::String(teacher)));
+        }
+        _ => return Err(Box::new(invalid())),
+    };
+    Ok(PatchOperator::Set(field, value))
+}
+
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![
+        IndexSpec::new("Comment", doc! {"cid": 1, "comment_by": 1}).unique(),
+        IndexSpec::new("Comment", doc! {"comment_by": 1}),
+    ]
+}
+
+async fn delete_comment(comments: &dyn CommentRepository, filter: Option<Document>, comment_by: &str) -> Result<i64, Box<dyn Error>> {
     let mut filter = filter.ok_or("delete operation cannot be done in bulk")?;
     filter.insert("comment_by", comment_by);
-    Ok(db
-        .cli
-        .database(&db.name)
-        .collection("Comment")
-        .delete_one( filter, None)
-        .await?
-        .deleted_count
-    )
-}
-
-pub async fn get_comment(db: Option<&Database>, filter: Option<Document>) -> Result<Vec<Comment>, Box<dyn Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
-    let filter = filter.unwrap_or(doc! {});
-    Ok(db
-        .cli
-        .database(&db.name)
-        .collection("Comment")
-        .find(filter, None)
+    comments.delete_comment(filter).await
+}
+
+pub async fn get_comment(comments: &dyn CommentRepository, filter: Option<Document>) -> Result<Vec<Comment>, Box<dyn Error>> {
+    let mut filter = filter.unwrap_or_default();
+    let sort = match filter.remove("sort") {
+        Some(Bson::String(sort)) => Some(sort.parse::<CommentSort>()?),
+        Some(sort) => return Err(Box::new(CommentError::UnknownSort(sort.to_string()))),
+        None => None,
+    };
+    let mut comments = comments
+        .find_comments(filter)
         .await?
-        .map(|d| {
-            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|d| {
-            from_bson::<Comment>(d.unwrap())
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|x| x.unwrap())
+        .into_iter()
         .map(|x| {
             let mut x = x;
             if !x.willing {
@@ -123,64 +353,88 @@ pub async fn get_comment(db: Option<&Database>, filter: Option<Document>) -> Res
             x.not_helpful = Some(x.not_helpful.unwrap_or(0));
             x
         })
-        .collect::<Vec<Comment>>()
+        .collect::<Vec<Comment>>();
+    if let Some(sort) = sort {
+        sort.sort(&mut comments);
+    }
+    Ok(comments)
+}
+
+// 用户导出自己的数据时不做 willing / anonymous 的遮蔽
+pub(crate) async fn get_comment_by(comments: &dyn CommentRepository, username: &str) -> Result<Vec<Comment>, Box<dyn Error>> {
+    comments.find_comments(doc! {"comment_by": username}).await
+}
+
+pub(crate) async fn delete_comment_by(comments: &dyn CommentRepository, username: &str) -> Result<i64, Box<dyn Error>> {
+    comments.delete_comments(doc! {"comment_by": username}).await
+}
+
+// 注销用户的评论改为匿名，comment_by 换成不可登录的占位名，保持 {cid, comment_by} 唯一
+pub(crate) async fn anonymize_comment_by(comments: &dyn CommentRepository, username: &str) -> Result<i64, Box<dyn Error>> {
+    let placeholder = format!("deleted:{}", Uuid::new_v4());
+    comments
+        .update_comments(doc! {"comment_by": username}, doc! {"$set": {"comment_by": placeholder, "anonymous": true}})
         .await
-    )
 }
 
-async fn delete_comment_handler(auth: BearerAuth, req: web::Json<Bson>) -> impl Responder {
+async fn delete_comment_handler(state: web::Data<AppState>, auth: BearerAuth, req: web::Json<Bson>) -> impl Responder {
     let session = json_response!(get_session(auth).await).data.unwrap();
-    web::Json(json_response!(delete_comment(None, req.as_document().cloned(), &session.username).await))
-}
-
-pub async fn post_comment(db: Option<&Database>, comment: &Comment) -> Result<Bson, Box<dyn Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
-    let cid = &comment.cid;
-    let comment_by = &comment.comment_by.as_ref().unwrap();
-    let comment = to_bson(comment)?.as_document().ok_or("failed to transfer Bson to Document")?.clone();
-    Ok(db
-        .cli
-        .database(&db.name)
-        .collection("Comment")
-        .replace_one(doc! {
-            "cid": cid,
-            "comment_by": comment_by
-        }, comment, ReplaceOptions::builder().upsert(true).build())
-        .await?
-        .upserted_id
-        .ok_or("comment upsert failed")?
-    )
+    web::Json(json_response!(timed("delete_comment", delete_comment(state.repos.comments.as_ref(), req.as_document().cloned(), &session.username)).await))
 }
 
-pub async fn patch_comment(db: Option<&Database>, filter: Document, op: PatchOperator) -> Result<Bson, Box<dyn Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
-
-    Ok(db
-        .cli
-        .database(&db.name)
-        .collection("Comment")
-        .update_one(filter, op.as_op(), None)
+pub async fn post_comment(repos: &Repositories, config: &CommentConfig, comment: Comment) -> Result<Bson, Box<dyn Error>> {
+    let now = Utc::now();
+    let mut comment = comment.validate(config, local_date(now))?;
+    if repos.courses.find_courses(doc! {"cid": &comment.cid}).await?.is_empty() {
+        return Err(Box::new(CommentError::CourseNotFound));
+    }
+    // Detail 里登记了开课学期时按它校验，没有登记的课程只检查年份范围
+    let offered = repos.details
+        .find_details(doc! {"cid": &comment.cid})
         .await?
-        .upserted_id
-        .ok_or("comment patch failed")?
-    )
+        .into_iter()
+        .flat_map(|detail| detail.offered)
+        .collect::<Vec<Offering>>();
+    if !offered.is_empty() && !offered.iter().any(|o| o.year == comment.year && o.term == comment.term) {
+        return Err(Box::new(CommentError::NotOffered));
+    }
+    let filter = doc! {
+        "cid": &comment.cid,
+        "comment_by": comment.comment_by.as_ref().unwrap()
+    };
+    // 重新提交时保留原来的创建时间和投票计数，客户端传来的时间戳和计数一律忽略
+    let stored = repos.comments.find_comments(filter.clone()).await?.into_iter().next();
+    comment.created_at = Some(stored.as_ref().and_then(|c| c.created_at).unwrap_or(bson::DateTime(now)));
+    comment.helpful = Some(stored.as_ref().and_then(|c| c.helpful).unwrap_or(0));
+    comment.not_helpful = Some(stored.as_ref().and_then(|c| c.not_helpful).unwrap_or(0));
+    comment.updated_at = Some(bson::DateTime(now));
+    let comment = to_bson(&comment)?.as_document().ok_or("failed to transfer Bson to Document")?.clone();
+    repos.comments.replace_comment(filter, comment).await
 }
 
-pub async fn get_comment_handler(req: web::Query<Bson>) -> impl Responder {
-    web::Json(json_response!(get_comment(None, req.as_document().cloned()).await))
+pub async fn patch_comment(comments: &dyn CommentRepository, config: &CommentConfig, filter: Document, op: PatchOperator) -> Result<i64, Box<dyn Error>> {
+    let mut update = check_patch(op, config)?.as_op();
+    let mut set = update.get_document("$set").cloned().unwrap_or_default();
+    set.insert("updated_at", Utc::now());
+    update.insert("$set", set);
+    comments.update_comment(filter, update).await
 }
 
-pub async fn post_comment_handler(auth: BearerAuth, mut comment: web::Json<Comment>) -> impl Responder {
+pub async fn get_comment_handler(state: web::Data<AppState>, req: web::Query<Bson>) -> impl Responder {
+    web::Json(json_response!(timed("get_comment", get_comment(state.repos.comments.as_ref(), req.as_document().cloned())).await))
+}
+
+pub async fn post_comment_handler(state: web::Data<AppState>, auth: BearerAuth, mut comment: web::Json<Comment>) -> impl Responder {
     let session = json_response!(get_session(auth).await).data.unwrap();
     comment.comment_by = Some(session.username);
-    web::Json(json_response!(post_comment(None, &comment.into_inner()).await))
+    web::Json(json_response!(timed("post_comment", post_comment(&state.repos, &state.policies.comment, comment.into_inner())).await))
 }
 
-pub async fn patch_comment_handler(auth: BearerAuth, req: web::Query<Bson>, op: web::Json<PatchOperator>) -> impl Responder {
+pub async fn patch_comment_handler(state: web::Data<AppState>, auth: BearerAuth, req: web::Query<Bson>, op: web::Json<PatchOperator>) -> impl Responder {
     let session = json_response!(get_session(auth).await).data.unwrap();
     let mut filter = req.as_document().cloned().unwrap_or(doc! {});
     filter.insert("comment_by", session.username);
-    web::Json(json_response!(patch_comment(None, filter, op.0).await))
+    web::Json(json_response!(timed("patch_comment", patch_comment(state.repos.comments.as_ref(), &state.policies.comment, filter, op.0)).await))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -191,4 +445,255 @@ pub fn config(cfg: &mut web::ServiceConfig) {
             .route(web::patch().to(patch_comment_handler))
             .route(web::delete().to(delete_comment_handler))
     );
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use actix_web::{App, test, web};
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use mongodb::bson::{self, Bson, doc};
+    use serde_json::json;
+
+    use crate::resources::comment::{check_patch, Comment, CommentSort, local_date, sanitize_content};
+    use crate::resources::session::Session;
+    use crate::resources::{comment, session};
+    use crate::util::config::CommentConfig;
+    use crate::util::json_response::JsonResponse;
+    use crate::util::memory_store::MemoryStore;
+    use crate::util::ops::PatchOperator;
+    use crate::util::state::AppState;
+
+    fn comment(content: &str, ratings: f32, year: i32, month: i32, day: i32) -> Comment {
+        serde_json::from_value(json!({
+            "gpa": "A", "cid": "CS101", "content": content, "term": "秋", "willing": false, "anonymous": true,
+            "rate": {"likes": 5.0, "useful": 4.0, "easy": 3.0, "ratings": ratings}, "taught": ["Alice"],
+            "helpful": null, "not_helpful": null, "year": year, "month": month, "day": day
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_sanitize_content() {
+        assert_eq!(sanitize_content("  <b>good</b> course "), "good course");
+        assert_eq!(sanitize_content("a<script>alert(1)</script>b<STYLE>p{}</STYLE>c"), "abc");
+        assert_eq!(sanitize_content("<img src=x onerror=alert(1)>ok"), "ok");
+        assert_eq!(sanitize_content("1 < 2 and 3 > 2 & 1"), "1 &lt; 2 and 3 &gt; 2 &amp; 1");
+        assert_eq!(sanitize_content("unclosed <a href='x"), "unclosed");
+        assert_eq!(sanitize_content("<<b>img src=x onerror=alert(1)>"), "");
+        assert_eq!(sanitize_content("a<scr<script>ipt>alert(1)</scr</script>ipt>b"), "ab");
+        assert_eq!(sanitize_content("<scr<script>ipt>alert(1)"), "");
+        assert_eq!(sanitize_content("&lt;b&gt;bold&lt;/b&gt;"), "bold");
+        assert_eq!(sanitize_content("AT&amp;amp;T"), "AT&amp;amp;T");
+    }
+
+    #[test]
+    fn test_sanitize_content_round_trip() {
+        let inputs = [
+            "1 < 2 and 3 > 2 & 1", "<<b>img src=x onerror=alert(1)>", "AT&T &amp; &lt;3", "&amp;lt;script&amp;gt;",
+            "a<scr<script>ipt>alert(1)</scr</script>ipt>b", "<< >> <3 & &&", "  <b>good</b> course ",
+        ];
+        for input in inputs.iter() {
+            let once = sanitize_content(input);
+            assert_eq!(sanitize_content(&once), once, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_validate_comment() {
+        let config = CommentConfig { max_length: Some(10), ..CommentConfig::default() };
+        let today = NaiveDate::from_ymd(2020, 6, 1);
+        let valid = comment("<i>good</i>", 4.0, 2020, 1, 1).validate(&config, today).unwrap();
+        assert_eq!(valid.content, "good");
+        assert!(comment("<p></p>", 4.0, 2020, 1, 1).validate(&config, today).is_err());
+        assert!(comment("01234567890", 4.0, 2020, 1, 1).validate(&config, today).is_err());
+        assert!(comment("好评好评好评好评好评", 4.0, 2020, 1, 1).validate(&config, today).is_ok());
+        assert!(comment("good", 6.0, 2020, 1, 1).validate(&config, today).is_err());
+        let mut nan = comment("good", 4.0, 2020, 1, 1);
+        nan.rate.ratings = f32::NAN;
+        assert!(nan.validate(&config, today).is_err());
+        assert!(comment("good", 4.0, 2020, 2, 30).validate(&config, today).is_err());
+        assert!(comment("good", 4.0, 2020, 6, 2).validate(&config, today).is_err());
+        assert!(comment("good", 4.0, 2011, 1, 1).validate(&config, today).is_err());
+        assert!(comment("good", 4.0, 2020, -1, 1).validate(&config, today).is_err());
+    }
+
+    #[test]
+    fn test_local_date() {
+        // UTC 16:00 已经是北京时间第二天
+        assert_eq!(local_date(Utc.ymd(2020, 5, 31).and_hms(15, 59, 59)), NaiveDate::from_ymd(2020, 5, 31));
+        assert_eq!(local_date(Utc.ymd(2020, 5, 31).and_hms(16, 0, 0)), NaiveDate::from_ymd(2020, 6, 1));
+        assert_eq!(local_date(Utc.ymd(2020, 12, 31).and_hms(20, 0, 0)), NaiveDate::from_ymd(2021, 1, 1));
+    }
+
+    #[test]
+    fn test_comment_sort() {
+        let mut comments = vec![(1, 3.0, Some(10)), (5, 4.0, None), (5, 2.0, Some(20)), (0, 5.0, Some(30))]
+            .into_iter()
+            .map(|(helpful, ratings, created_at): (i64, f32, Option<i64>)| {
+                let mut c = comment("good", ratings, 2020, 1, 1);
+                c.helpful = Some(helpful);
+                c.created_at = created_at.map(|secs| bson::DateTime(Utc.timestamp(secs, 0)));
+                c
+            })
+            .collect::<Vec<Comment>>();
+        let ratings = |comments: &[Comment]| comments.iter().map(|c| c.rate.ratings).collect::<Vec<f32>>();
+        "newest".parse::<CommentSort>().unwrap().sort(&mut comments);
+        assert_eq!(ratings(&comments), vec![5.0, 2.0, 3.0, 4.0]);
+        "helpful".parse::<CommentSort>().unwrap().sort(&mut comments);
+        assert_eq!(ratings(&comments), vec![2.0, 4.0, 3.0, 5.0]);
+        "rating".parse::<CommentSort>().unwrap().sort(&mut comments);
+        assert_eq!(ratings(&comments), vec![5.0, 4.0, 3.0, 2.0]);
+        assert!("oldest".parse::<CommentSort>().is_err());
+    }
+
+    #[test]
+    fn test_check_patch() {
+        let config = CommentConfig::default();
+        let op = check_patch(PatchOperator::Set("content".to_string(), Bson::String(" <b>ok</b>".to_string())), &config).unwrap();
+        assert_eq!(op.as_op(), doc! {"$set": {"content": "ok"}});
+        assert!(check_patch(PatchOperator::Set("content".to_string(), Bson::String("<b></b>".to_string())), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("content".to_string(), Bson::Int32(1)), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("rate.easy".to_string(), Bson::Double(9.0)), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("rate.easy".to_string(), Bson::Int32(3)), &config).is_ok());
+        assert!(check_patch(PatchOperator::Inc("rate.easy".to_string(), 3), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("year".to_string(), Bson::Int32(2030)), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("cid".to_string(), Bson::String("CS102".to_string())), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("gpa".to_string(), Bson::String("B".to_string())), &config).is_ok());
+        assert!(check_patch(PatchOperator::Set("gpa".to_string(), Bson::Null), &config).is_ok());
+        assert!(check_patch(PatchOperator::Set("gpa".to_string(), Bson::Int32(1)), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("gpa".to_string(), Bson::String("S".to_string())), &config).is_err());
+        assert!(check_patch(PatchOperator::Inc("helpful".to_string(), 1000), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("not_helpful".to_string(), Bson::Int32(0)), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("rate.hard".to_string(), Bson::Int32(3)), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("willing".to_string(), Bson::Boolean(true)), &config).is_ok());
+        assert!(check_patch(PatchOperator::Set("anonymous".to_string(), Bson::String("yes".to_string())), &config).is_err());
+        assert!(check_patch(PatchOperator::AddToSet("taught".to_string(), Bson::String("Bob".to_string())), &config).is_ok());
+        assert!(check_patch(PatchOperator::AddToSet("taught".to_string(), Bson::Int32(1)), &config).is_err());
+        assert!(check_patch(PatchOperator::Set("taught".to_string(), Bson::Array(vec![Bson::Int32(1)])), &config).is_err());
+        let op = doc! {"likes": 5, "useful": 4.0, "easy": 3.0, "ratings": 4.0, "extra": 1};
+        let op = check_patch(PatchOperator::Set("rate".to_string(), Bson::Document(op)), &config).unwrap();
+        assert!(!op.as_op().get_document("$set").unwrap().get_document("rate").unwrap().contains_key("extra"));
+    }
+
+    #[actix_rt::test]
+    async fn test_comment_handlers_in_memory() {
+        let store = Arc::new(MemoryStore::new());
+        store.insert("User", doc! {
+            "username": "memory_user",
+            "email": "11111111@mail.sustech.edu.cn",
+            "permanent_token": bcrypt::hash("secret", 4).unwrap(),
+            "learnt_course": [],
+        }).unwrap();
+        store.insert("Course", doc! {"cid": "CS101", "name": "计算机导论", "faculty": "计算机系", "taught_by": ["Alice"]}).unwrap();
+        store.insert("Course", doc! {"cid": "CS102", "name": "程序设计", "faculty": "计算机系", "taught_by": ["Alice"]}).unwrap();
+        store.insert("Detail", doc! {
+            "cid": "CS102", "name": "程序设计", "english_name": "", "open_by": "计算机系", "credit": "3", "detail": "",
+            "offered": [{"year": 2019, "term": "秋"}, {"year": 2020, "term": "春"}],
+        }).unwrap();
+        let state = web::Data::new(AppState::memory(store.clone()).await.unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .app_data(state)
+                .configure(session::config)
+                .configure(comment::config)
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/session")
+            .set_json(&json!({"username": "memory_user", "password": "secret"}))
+            .to_request();
+        let res: JsonResponse<Session> = test::read_response_json(&mut app, req).await;
+        let token = res.data.unwrap().token;
+
+        let req = test::TestRequest::post()
+            .uri("/comment")
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({
+                "gpa": "A", "cid": "CS101", "content": "good", "term": "秋", "willing": false, "anonymous": true,
+                "rate": {"likes": 5.0, "useful": 4.0, "easy": 3.0, "ratings": 4.0}, "taught": ["Alice"],
+                "helpful": 100, "not_helpful": null, "year": 2020, "month": 1, "day": 1
+            }))
+            .to_request();
+        let res: JsonResponse<serde_json::Value> = test::read_response_json(&mut app, req).await;
+        assert!(res.error.is_none(), "{:?}", res.error);
+        let id = res.data.unwrap();
+
+        let req = test::TestRequest::post()
+            .uri("/comment")
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({
+                "gpa": "A", "cid": "CS999", "content": "good", "term": "秋", "willing": false, "anonymous": true,
+                "rate": {"likes": 5.0, "useful": 4.0, "easy": 3.0, "ratings": 4.0}, "taught": ["Alice"],
+                "helpful": null, "not_helpful": null, "year": 2020, "month": 1, "day": 1
+            }))
+            .to_request();
+        let res: JsonResponse<serde_json::Value> = test::read_response_json(&mut app, req).await;
+        assert_eq!(res.error.unwrap(), "course not found");
+
+        // CS102 只在 2019 秋、2020 春开过
+        for (term, year, offered) in vec![("秋", 2020, false), ("春", 2019, false), ("春", 2020, true)] {
+            let req = test::TestRequest::post()
+                .uri("/comment")
+                .header("Authorization", format!("Bearer {}", token))
+                .set_json(&json!({
+                    "gpa": "A", "cid": "CS102", "content": "good", "term": term, "willing": false, "anonymous": true,
+                    "rate": {"likes": 5.0, "useful": 4.0, "easy": 3.0, "ratings": 4.0}, "taught": ["Alice"],
+                    "helpful": null, "not_helpful": null, "year": year, "month": 1, "day": 1
+                }))
+                .to_request();
+            let res: JsonResponse<serde_json::Value> = test::read_response_json(&mut app, req).await;
+            assert_eq!(res.error.is_none(), offered, "{} {} {:?}", year, term, res.error);
+        }
+
+        let req = test::TestRequest::get().uri("/comment?cid=CS101").to_request();
+        let res: JsonResponse<Vec<Comment>> = test::read_response_json(&mut app, req).await;
+        let comments = res.data.unwrap();
+        assert_eq!(comments.len(), 1);
+        assert!(comments[0].gpa.is_none() && comments[0].comment_by.is_none());
+        assert_eq!((comments[0].helpful, comments[0].not_helpful), (Some(0), Some(0)));
+        let created_at = comments[0].created_at.unwrap();
+        assert_eq!(comments[0].updated_at, Some(created_at));
+        assert_eq!(store.find("Comment", &doc! {"cid": "CS101", "comment_by": "memory_user"}).unwrap().len(), 1);
+
+        let req = test::TestRequest::patch()
+            .uri("/comment?cid=CS101")
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({"Set": ["content", "better"]}))
+            .to_request();
+        let res: JsonResponse<i64> = test::read_response_json(&mut app, req).await;
+        assert_eq!(res.data, Some(1));
+
+        let req = test::TestRequest::get().uri("/comment?cid=CS101&sort=newest").to_request();
+        let res: JsonResponse<Vec<Comment>> = test::read_response_json(&mut app, req).await;
+        let comments = res.data.unwrap();
+        assert_eq!(comments[0].created_at, Some(created_at));
+        assert!(comments[0].updated_at.unwrap() >= created_at);
+
+        // 重新提交同一课程的评论：返回原来的 _id，保留创建时间和别人的投票
+        store.update("Comment", &doc! {"cid": "CS101", "comment_by": "memory_user"}, &doc! {"$set": {"helpful": 7, "not_helpful": 2}}, false).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/comment")
+            .header("Authorization", format!("Bearer {}", token))
+            .set_json(&json!({
+                "gpa": "A", "cid": "CS101", "content": "again", "term": "秋", "willing": false, "anonymous": true,
+                "rate": {"likes": 5.0, "useful": 4.0, "easy": 3.0, "ratings": 4.0}, "taught": ["Alice"],
+                "helpful": 0, "not_helpful": 0, "year": 2020, "month": 1, "day": 1,
+                "created_at": {"$date": "2012-01-01T00:00:00Z"}
+            }))
+            .to_request();
+        let res: JsonResponse<serde_json::Value> = test::read_response_json(&mut app, req).await;
+        assert!(res.error.is_none(), "{:?}", res.error);
+        assert_eq!(res.data.unwrap(), id);
+        let req = test::TestRequest::get().uri("/comment?cid=CS101").to_request();
+        let res: JsonResponse<Vec<Comment>> = test::read_response_json(&mut app, req).await;
+        let comments = res.data.unwrap();
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].content, "again");
+        assert_eq!((comments[0].helpful, comments[0].not_helpful), (Some(7), Some(2)));
+        assert_eq!(comments[0].created_at, Some(created_at));
+
+        let req = test::TestRequest::get().uri("/comment?cid=CS101&sort=oldest").to_request();
+        let res: JsonResponse<Vec<Comment>> = test::read_response_json(&mut app, req).await;
+        assert!(res.data.is_none());
+    }
+}
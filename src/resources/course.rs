@@ -1,73 +1,428 @@
+use core::fmt;
+use std::error::Error;
+
 use actix_web::{Responder, web};
-use futures::future;
+use actix_web_httpauth::extractors::bearer::BearerAuth;
 use futures::stream::StreamExt;
-use futures_await_test::async_test;
-use mongodb::bson::{Bson, doc, Document, from_bson};
+use mongodb::bson::{Bson, doc, Document, from_bson, to_bson};
 use serde::{Deserialize, Serialize};
 
 use crate::json_response;
+use crate::resources::detail::Detail;
+use crate::resources::rate::Rate;
+use crate::resources::session::get_admin_session;
 use crate::util::database::Database;
-use crate::util::database::DEFAULT_DATABASE;
+use crate::util::index::IndexSpec;
+use crate::util::metrics::timed;
+use crate::util::ops::PatchOperator;
+use crate::util::repository::CourseRepository;
+use crate::util::state::AppState;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Course {
+    pub(crate) cid: String,
+    pub(crate) name: String,
+    pub(crate) taught_by: Vec<Vec<String>>,
+    pub(crate) faculty: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct CourseSection {
+    cid: String,
+    name: String,
+    taught_by: Vec<String>,
+    faculty: String,
+}
+
+#[derive(Debug)]
+pub enum CatalogError {
+    MissingField(&'static str),
+    InvalidCredit,
+    Exists,
+    NotFound,
+    ReadOnly(String),
+    InvalidValue(String),
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CatalogError::MissingField(field) => write!(f, "{} is required", field),
+            CatalogError::InvalidCredit => write!(f, "credit must be a number"),
+            CatalogError::Exists => write!(f, "course already exists"),
+            CatalogError::NotFound => write!(f, "course not found"),
+            CatalogError::ReadOnly(field) => write!(f, "{} cannot be patched", field),
+            CatalogError::InvalidValue(field) => write!(f, "{} must be set to a string, required fields cannot be empty", field),
+        }
+    }
+}
+
+impl Error for CatalogError {}
+
+// PATCH 只能 Set patchable 中的字符串字段，required 中的不能为空；
+// 其余字段（包括按教学班存的 taught_by）用 PUT 整体替换
+pub(crate) fn check_catalog_patch(op: &PatchOperator, patchable: &[&str], required: &[&str]) -> Result<(), CatalogError> {
+    match op {
+        PatchOperator::Set(field, Bson::String(value)) if patchable.contains(&field.as_str()) => {
+            if required.contains(&field.as_str()) && value.trim().is_empty() {
+                Err(CatalogError::InvalidValue(field.clone()))
+            } else {
+                Ok(())
+            }
+        }
+        PatchOperator::Set(field, _) if patchable.contains(&field.as_str()) => Err(CatalogError::InvalidValue(field.clone())),
+        PatchOperator::AddToSet(field, _) | PatchOperator::Set(field, _)
+        | PatchOperator::Inc(field, _) | PatchOperator::RmFromSet(field, _) => Err(CatalogError::ReadOnly(field.clone())),
+    }
+}
+
+impl Course {
+    pub(crate) fn validate(&self) -> Result<(), CatalogError> {
+        if self.cid.trim().is_empty() {
+            Err(CatalogError::MissingField("cid"))
+        } else if self.name.trim().is_empty() {
+            Err(CatalogError::MissingField("name"))
+        } else if self.faculty.trim().is_empty() {
+            Err(CatalogError::MissingField("faculty"))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn sections(&self) -> Vec<CourseSection> {
+        let groups = if self.taught_by.is_empty() { vec![vec![]] } else { self.taught_by.clone() };
+        groups
+            .into_iter()
+            .map(|taught_by| CourseSection {
+                cid: self.cid.clone(),
+                name: self.name.clone(),
+                taught_by,
+                faculty: self.faculty.clone(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CourseView {
     cid: String,
     name: String,
     taught_by: Vec<Vec<String>>,
     faculty: String,
+    detail: Option<Detail>,
+    rate: Option<Rate>,
+    comment_count: u32,
 }
 
-async fn get_course(db: Option<&Database>, filter: Option<Document>) -> Result<Vec<Course>, Box<dyn std::error::Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
-    let filter = doc! { "$match": filter.unwrap_or(doc!{}) };
-    let aggregator = doc! {
-                "$group" : {
-                    "_id" : "$cid",
-                    "cid" : {"$first": "$cid"},
-                    "name" : {"$first": "$name"},
-                    "faculty" : {"$first": "$faculty"},
-                    "taught_by" : {"$addToSet": "$taught_by"},
-                }
-            };
-    Ok(db
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![
+        IndexSpec::new("Course", doc! {"cid": 1}),
+        IndexSpec::new("Course", doc! {"faculty": 1}),
+    ]
+}
+
+pub(crate) async fn get_course(courses: &dyn CourseRepository, filter: Option<Document>) -> Result<Vec<Course>, Box<dyn std::error::Error>> {
+    courses.find_courses(filter.unwrap_or(doc! {})).await
+}
+
+pub async fn get_course_view(db: &Database, cid: &str) -> Result<CourseView, Box<dyn std::error::Error>> {
+    let pipeline = vec![
+        doc! { "$match": {"cid": cid} },
+        doc! {
+            "$group" : {
+                "_id" : "$cid",
+                "cid" : {"$first": "$cid"},
+                "name" : {"$first": "$name"},
+                "faculty" : {"$first": "$faculty"},
+                "taught_by" : {"$addToSet": "$taught_by"},
+            }
+        },
+        doc! { "$lookup": {"from": "Detail", "localField": "cid", "foreignField": "cid", "as": "detail"} },
+        doc! { "$lookup": {"from": "Rate", "localField": "cid", "foreignField": "cid", "as": "rate"} },
+        doc! {
+            "$lookup": {
+                "from": "Comment",
+                "let": {"cid": "$cid"},
+                "pipeline": [
+                    {"$match": {"$expr": {"$eq": ["$cid", "$$cid"]}}},
+                    {"$count": "count"},
+                ],
+                "as": "comments",
+            }
+        },
+        doc! {
+            "$addFields": {
+                "detail": {"$arrayElemAt": ["$detail", 0]},
+                "rate": {"$arrayElemAt": ["$rate", 0]},
+                "comment_count": {"$ifNull": [{"$arrayElemAt": ["$comments.count", 0]}, 0]},
+            }
+        },
+        doc! { "$project": {"comments": 0} },
+    ];
+    let view = db
         .cli
         .database(&db.name)
         .collection("Course")
-        .aggregate(
-            vec![filter, aggregator],
-            None,
-        )
+        .aggregate(pipeline, None)
         .await?
-        .map(|d| {
-            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|d| {
-            from_bson::<Course>(d.unwrap())
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|x| x.unwrap())
-        .collect::<Vec<Course>>()
+        .next()
         .await
-    )
+        .ok_or("course not found")??;
+    Ok(from_bson::<CourseView>(Bson::Document(view))?)
+}
+
+// Course 中每个教学班一条记录，name / faculty 需要和 Detail 的 name / open_by 保持一致
+pub(crate) async fn sync_detail(db: &Database, cid: &str, field: &str, value: &Bson) -> Result<(), Box<dyn Error>> {
+    let field = match field {
+        "name" => "name",
+        "faculty" => "open_by",
+        _ => return Ok(()),
+    };
+    db.cli
+        .database(&db.name)
+        .collection("Detail")
+        .update_many(doc! {"cid": cid}, doc! {"$set": {field: value.clone()}}, None)
+        .await?;
+    Ok(())
+}
+
+// 返回新插入教学班的 _id
+async fn write_sections(db: &Database, course: &Course) -> Result<Vec<Bson>, Box<dyn Error>> {
+    let sections = course
+        .sections()
+        .iter()
+        .map(|section| Ok(to_bson(section)?.as_document().ok_or("failed to transfer Bson to Document")?.clone()))
+        .collect::<Result<Vec<Document>, Box<dyn Error>>>()?;
+    let inserted = db
+        .cli
+        .database(&db.name)
+        .collection("Course")
+        .insert_many(sections, None)
+        .await?
+        .inserted_ids
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect();
+    sync_detail(db, &course.cid, "name", &Bson::String(course.name.clone())).await?;
+    sync_detail(db, &course.cid, "faculty", &Bson::String(course.faculty.clone())).await?;
+    Ok(inserted)
+}
+
+pub async fn post_course(db: &Database, course: &Course) -> Result<i64, Box<dyn Error>> {
+    course.validate()?;
+    let exists = db
+        .cli
+        .database(&db.name)
+        .collection("Course")
+        .count_documents(doc! {"cid": &course.cid}, None)
+        .await?;
+    if exists > 0 {
+        return Err(Box::new(CatalogError::Exists));
+    }
+    Ok(write_sections(db, course).await?.len() as i64)
+}
+
+pub async fn put_course(db: &Database, cid: &str, course: &Course) -> Result<i64, Box<dyn Error>> {
+    course.validate()?;
+    if course.cid != cid {
+        return Err(Box::new(CatalogError::ReadOnly("cid".to_string())));
+    }
+    // 先插入新教学班再删除旧的：插入失败时旧数据还在，删除失败时重试 PUT 即可
+    let inserted = write_sections(db, course).await?;
+    let count = inserted.len() as i64;
+    db.cli
+        .database(&db.name)
+        .collection("Course")
+        .delete_many(doc! {"cid": cid, "_id": {"$nin": inserted}}, None)
+        .await?;
+    Ok(count)
+}
+
+pub async fn patch_course(db: &Database, cid: &str, op: PatchOperator) -> Result<i64, Box<dyn Error>> {
+    check_catalog_patch(&op, &["name", "faculty"], &["name", "faculty"])?;
+    let result = db
+        .cli
+        .database(&db.name)
+        .collection("Course")
+        .update_many(doc! {"cid": cid}, op.as_op(), None)
+        .await?;
+    if result.matched_count == 0 {
+        return Err(Box::new(CatalogError::NotFound));
+    }
+    if let PatchOperator::Set(field, value) = &op {
+        sync_detail(db, cid, field, value).await?;
+    }
+    Ok(result.modified_count)
+}
+
+// Detail 依附于课程，课程删除后一并删除；反过来删除 Detail 不影响课程，见 delete_detail
+pub async fn delete_course(db: &Database, cid: &str) -> Result<i64, Box<dyn Error>> {
+    let deleted = db
+        .cli
+        .database(&db.name)
+        .collection("Course")
+        .delete_many(doc! {"cid": cid}, None)
+        .await?
+        .deleted_count;
+    let details = db
+        .cli
+        .database(&db.name)
+        .collection("Detail")
+        .delete_many(doc! {"cid": cid}, None)
+        .await?
+        .deleted_count;
+    if deleted == 0 && details == 0 {
+        return Err(Box::new(CatalogError::NotFound));
+    }
+    Ok(deleted)
+}
+
+async fn get_course_handler(state: web::Data<AppState>, req: web::Query<Bson>) -> impl Responder {
+    web::Json(json_response!(timed("get_course", get_course(state.repos.courses.as_ref(), req.as_document().cloned())).await))
+}
+
+async fn get_course_view_handler(state: web::Data<AppState>, cid: web::Path<String>) -> impl Responder {
+    web::Json(json_response!(timed("get_course_view", get_course_view(&state.db, &cid)).await))
+}
+
+async fn post_course_handler(state: web::Data<AppState>, auth: BearerAuth, course: web::Json<Course>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("post_course", post_course(&state.db, &course)).await))
 }
 
-async fn get_course_handler(req: web::Query<Bson>) -> impl Responder {
-    web::Json(json_response!(get_course(None, req.as_document().cloned()).await))
+async fn put_course_handler(state: web::Data<AppState>, auth: BearerAuth, cid: web::Path<String>, course: web::Json<Course>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("put_course", put_course(&state.db, &cid, &course)).await))
+}
+
+async fn patch_course_handler(state: web::Data<AppState>, auth: BearerAuth, cid: web::Path<String>, op: web::Json<PatchOperator>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("patch_course", patch_course(&state.db, &cid, op.0)).await))
+}
+
+async fn delete_course_handler(state: web::Data<AppState>, auth: BearerAuth, cid: web::Path<String>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("delete_course", delete_course(&state.db, &cid)).await))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/course")
             .route(web::get().to(get_course_handler))
+            .route(web::post().to(post_course_handler))
+    );
+    cfg.service(
+        web::resource("/course/{cid}")
+            .route(web::get().to(get_course_view_handler))
+            .route(web::put().to(put_course_handler))
+            .route(web::patch().to(patch_course_handler))
+            .route(web::delete().to(delete_course_handler))
     );
 }
 
-#[async_test]
-async fn test_get_course_10_times() {
-    for _ in 0..10 {
-        get_course(None, None).await.unwrap();
+#[cfg(test)]
+mod test {
+    use futures_await_test::async_test;
+    use mongodb::bson::{Bson, doc};
+
+    use crate::resources::course::{check_catalog_patch, Course, delete_course, get_course, get_course_view, patch_course, post_course, put_course};
+    use crate::resources::detail::{Detail, get_detail, post_detail};
+    use crate::util::ops::PatchOperator;
+    use crate::util::testing::TestApp;
+
+    fn course(cid: &str, name: &str, faculty: &str, taught_by: &[&[&str]]) -> Course {
+        Course {
+            cid: cid.to_string(),
+            name: name.to_string(),
+            taught_by: taught_by.iter().map(|t| t.iter().map(|s| s.to_string()).collect()).collect(),
+            faculty: faculty.to_string(),
+        }
     }
-}
 
+    #[test]
+    fn test_check_catalog_patch() {
+        let set = |field: &str, value: Bson| PatchOperator::Set(field.to_string(), value);
+        let fields = ["name", "faculty", "english_name"];
+        let required = ["name", "faculty"];
+        assert!(check_catalog_patch(&set("name", Bson::String("数据结构".to_string())), &fields, &required).is_ok());
+        assert!(check_catalog_patch(&set("english_name", Bson::String(String::new())), &fields, &required).is_ok());
+        assert!(check_catalog_patch(&set("name", Bson::String(" ".to_string())), &fields, &required).is_err());
+        assert!(check_catalog_patch(&set("faculty", Bson::Int32(1)), &fields, &required).is_err());
+        assert!(check_catalog_patch(&set("taught_by", Bson::String("x".to_string())), &fields, &required).is_err());
+        assert!(check_catalog_patch(&set("cid", Bson::String("CS102".to_string())), &fields, &required).is_err());
+        assert!(check_catalog_patch(&PatchOperator::AddToSet("name".to_string(), Bson::String("x".to_string())), &fields, &required).is_err());
+    }
+
+    #[async_test]
+    async fn test_get_course_view() {
+        let app = TestApp::new().await.unwrap();
+        app.seed_catalog().await.unwrap();
+
+        let view = get_course_view(app.db(), "CS101").await.unwrap();
+        assert_eq!(view.taught_by.len(), 2);
+        assert_eq!(view.comment_count, 2);
+        assert_eq!(view.detail.as_ref().unwrap().credit, "3");
+        assert_eq!(serde_json::to_value(&view).unwrap()["rate"]["likes"], 5.0);
 
+        // 没有 Detail 和 Rate 的课程照样返回
+        let view = get_course_view(app.db(), "CS102").await.unwrap();
+        assert_eq!(view.name, "程序设计");
+        assert!(view.detail.is_none());
+        assert!(view.rate.is_none());
+        assert_eq!(view.comment_count, 1);
+
+        assert!(get_course_view(app.db(), "CS999").await.is_err());
+    }
+
+    #[async_test]
+    async fn test_get_course_10_times() {
+        let app = TestApp::new().await.unwrap();
+        for _ in 0..10 {
+            get_course(app.db(), None).await.unwrap();
+        }
+    }
+
+    #[async_test]
+    async fn test_course_crud_syncs_detail() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        post_detail(db, &Detail {
+            cid: "CS201".to_string(),
+            name: "旧名".to_string(),
+            english_name: "Data Structures".to_string(),
+            open_by: "旧院系".to_string(),
+            credit: "3".to_string(),
+            detail: String::new(),
+            offered: vec![],
+        }).await.unwrap();
+        let detail = || async { get_detail(app.db(), Some(doc! {"cid": "CS201"})).await.unwrap().pop().unwrap() };
+        let sections = || async { get_course(app.db(), Some(doc! {"cid": "CS201"})).await.unwrap() };
+
+        assert_eq!(post_course(db, &course("CS201", "数据结构", "计算机系", &[&["Alice"], &["Bob"]])).await.unwrap(), 2);
+        assert!(post_course(db, &course("CS201", "数据结构", "计算机系", &[])).await.is_err());
+        assert!(post_course(db, &course("CS202", "", "计算机系", &[])).await.is_err());
+        assert_eq!(detail().await.name, "数据结构");
+        assert_eq!(detail().await.open_by, "计算机系");
+
+        assert!(put_course(db, "CS201", &course("CS999", "数据结构", "计算机系", &[])).await.is_err());
+        assert_eq!(put_course(db, "CS201", &course("CS201", "数据结构与算法", "计算机系", &[&["Carol"]])).await.unwrap(), 1);
+        let courses = sections().await;
+        assert_eq!(courses.len(), 1);
+        assert_eq!(courses[0].taught_by, vec![vec!["Carol".to_string()]]);
+        assert_eq!(detail().await.name, "数据结构与算法");
+
+        let set = |field: &str, value: Bson| PatchOperator::Set(field.to_string(), value);
+        assert_eq!(patch_course(db, "CS201", set("faculty", Bson::String("数学系".to_string()))).await.unwrap(), 1);
+        assert_eq!(sections().await[0].faculty, "数学系");
+        assert_eq!(detail().await.open_by, "数学系");
+        assert!(patch_course(db, "CS201", set("faculty", Bson::Int32(1))).await.is_err());
+        assert!(patch_course(db, "CS201", set("taught_by", Bson::String("x".to_string()))).await.is_err());
+        assert!(patch_course(db, "CS999", set("name", Bson::String("x".to_string()))).await.is_err());
+        assert_eq!(sections().await.len(), 1);
+
+        assert_eq!(delete_course(db, "CS201").await.unwrap(), 1);
+        assert!(delete_course(db, "CS201").await.is_err());
+        assert!(sections().await.is_empty());
+        assert!(get_detail(app.db(), Some(doc! {"cid": "CS201"})).await.unwrap().is_empty());
+    }
+}
@@ -1,51 +1,246 @@
 use crate::util::database::Database;
-use futures::stream::StreamExt;
-use futures::future;
 use actix_web::{web, Responder};
-use mongodb::bson::{Bson, doc, Document, from_bson};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use mongodb::bson::{Bson, doc, Document, to_bson};
+use mongodb::options::ReplaceOptions;
 use serde::{Deserialize, Serialize};
-use crate::util::database::DEFAULT_DATABASE;
+use crate::json_response;
+use crate::resources::comment::Term;
+use crate::resources::course::{CatalogError, check_catalog_patch};
+use crate::resources::session::get_admin_session;
+use crate::util::index::IndexSpec;
+use crate::util::metrics::timed;
+use crate::util::ops::PatchOperator;
+use crate::util::repository::DetailRepository;
+use crate::util::state::AppState;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Offering {
+    pub(crate) year: i32,
+    pub(crate) term: Term,
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Detail {
-    cid: String,
-    name: String,
-    english_name: String,
-    open_by: String,
-    credit: String,
-    detail: String,
-}
-
-async fn get_detail(db: Option<&Database>, filter: Option<Document>) -> Result<Vec<Detail>, Box<dyn std::error::Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
-    let filter = filter.unwrap_or(doc! {});
-    Ok(db
+    pub(crate) cid: String,
+    pub(crate) name: String,
+    pub(crate) english_name: String,
+    pub(crate) open_by: String,
+    pub(crate) credit: String,
+    pub(crate) detail: String,
+    // 开课学期，评论的 year / term 须在其中；为空时不限制
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) offered: Vec<Offering>,
+}
+
+impl Detail {
+    pub(crate) fn validate(&self) -> Result<(), CatalogError> {
+        if self.cid.trim().is_empty() {
+            Err(CatalogError::MissingField("cid"))
+        } else if self.name.trim().is_empty() {
+            Err(CatalogError::MissingField("name"))
+        } else if self.open_by.trim().is_empty() {
+            Err(CatalogError::MissingField("open_by"))
+        } else if self.credit.trim().is_empty() {
+            Err(CatalogError::MissingField("credit"))
+        } else if self.credit.trim().parse::<f32>().is_err() {
+            Err(CatalogError::InvalidCredit)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![IndexSpec::new("Detail", doc! {"cid": 1}).unique()]
+}
+
+pub(crate) async fn get_detail(details: &dyn DetailRepository, filter: Option<Document>) -> Result<Vec<Detail>, Box<dyn std::error::Error>> {
+    details.find_details(filter.unwrap_or(doc! {})).await
+}
+
+// Detail 的 name / open_by 同步到 Course 的每个教学班
+async fn sync_course(db: &Database, cid: &str, field: &str, value: &Bson) -> Result<(), Box<dyn std::error::Error>> {
+    let field = match field {
+        "name" => "name",
+        "open_by" => "faculty",
+        _ => return Ok(()),
+    };
+    db.cli
+        .database(&db.name)
+        .collection("Course")
+        .update_many(doc! {"cid": cid}, doc! {"$set": {field: value.clone()}}, None)
+        .await?;
+    Ok(())
+}
+
+async fn write_detail(db: &Database, detail: &Detail) -> Result<Bson, Box<dyn std::error::Error>> {
+    let document = to_bson(detail)?.as_document().ok_or("failed to transfer Bson to Document")?.clone();
+    db.cli
+        .database(&db.name)
+        .collection("Detail")
+        .replace_one(doc! {"cid": &detail.cid}, document, ReplaceOptions::builder().upsert(true).build())
+        .await?;
+    sync_course(db, &detail.cid, "name", &Bson::String(detail.name.clone())).await?;
+    sync_course(db, &detail.cid, "open_by", &Bson::String(detail.open_by.clone())).await?;
+    Ok(Bson::String(detail.cid.clone()))
+}
+
+pub async fn post_detail(db: &Database, detail: &Detail) -> Result<Bson, Box<dyn std::error::Error>> {
+    detail.validate()?;
+    let exists = db
+        .cli
+        .database(&db.name)
+        .collection("Detail")
+        .count_documents(doc! {"cid": &detail.cid}, None)
+        .await?;
+    if exists > 0 {
+        return Err(Box::new(CatalogError::Exists));
+    }
+    write_detail(db, detail).await
+}
+
+pub async fn put_detail(db: &Database, cid: &str, detail: &Detail) -> Result<Bson, Box<dyn std::error::Error>> {
+    detail.validate()?;
+    if detail.cid != cid {
+        return Err(Box::new(CatalogError::ReadOnly("cid".to_string())));
+    }
+    write_detail(db, detail).await
+}
+
+pub async fn patch_detail(db: &Database, cid: &str, op: PatchOperator) -> Result<i64, Box<dyn std::error::Error>> {
+    check_catalog_patch(&op, &["name", "english_name", "open_by", "credit", "detail"], &["name", "open_by", "credit"])?;
+    if let PatchOperator::Set(field, Bson::String(credit)) = &op {
+        if field == "credit" && credit.trim().parse::<f32>().is_err() {
+            return Err(Box::new(CatalogError::InvalidCredit));
+        }
+    }
+    let result = db
         .cli
         .database(&db.name)
         .collection("Detail")
-        .find(filter, None)
+        .update_one(doc! {"cid": cid}, op.as_op(), None)
+        .await?;
+    if result.matched_count == 0 {
+        return Err(Box::new(CatalogError::NotFound));
+    }
+    if let PatchOperator::Set(field, value) = &op {
+        sync_course(db, cid, field, value).await?;
+    }
+    Ok(result.modified_count)
+}
+
+// 只删除详细信息：没有 Detail 的课程仍是有效课程（导入时常见），课程视图里 detail 为空。
+// 教学班来自选课数据，要删课程用 delete_course，它会连带删除 Detail
+pub async fn delete_detail(db: &Database, cid: &str) -> Result<i64, Box<dyn std::error::Error>> {
+    let deleted = db
+        .cli
+        .database(&db.name)
+        .collection("Detail")
+        .delete_one(doc! {"cid": cid}, None)
         .await?
-        .map(|d| {
-            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|d| {
-            from_bson::<Detail>(d.unwrap())
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|x| x.unwrap())
-        .collect::<Vec<Detail>>()
-        .await)
+        .deleted_count;
+    if deleted == 0 {
+        return Err(Box::new(CatalogError::NotFound));
+    }
+    Ok(deleted)
+}
+
+async fn get_detail_handler(state: web::Data<AppState>, req: web::Query<Bson>) -> impl Responder {
+    web::Json(json_response!(timed("get_detail", get_detail(state.repos.details.as_ref(), req.as_document().cloned())).await))
+}
+
+async fn post_detail_handler(state: web::Data<AppState>, auth: BearerAuth, detail: web::Json<Detail>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("post_detail", post_detail(&state.db, &detail)).await))
+}
+
+async fn put_detail_handler(state: web::Data<AppState>, auth: BearerAuth, cid: web::Path<String>, detail: web::Json<Detail>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("put_detail", put_detail(&state.db, &cid, &detail)).await))
+}
+
+async fn patch_detail_handler(state: web::Data<AppState>, auth: BearerAuth, cid: web::Path<String>, op: web::Json<PatchOperator>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("patch_detail", patch_detail(&state.db, &cid, op.0)).await))
 }
 
-async fn get_detail_handler(req: web::Query<Bson>) -> impl Responder {
-    use crate::json_response;
-    web::Json(json_response!(get_detail(None, req.as_document().cloned()).await))
+async fn delete_detail_handler(state: web::Data<AppState>, auth: BearerAuth, cid: web::Path<String>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("delete_detail", delete_detail(&state.db, &cid)).await))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/detail")
             .route(web::get().to(get_detail_handler))
+            .route(web::post().to(post_detail_handler))
     );
-}
\ No newline at end of file
+    cfg.service(
+        web::resource("/detail/{cid}")
+            .route(web::put().to(put_detail_handler))
+            .route(web::patch().to(patch_detail_handler))
+            .route(web::delete().to(delete_detail_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use futures_await_test::async_test;
+    use mongodb::bson::{Bson, doc};
+
+    use crate::resources::course::{Course, get_course, post_course};
+    use crate::resources::detail::{delete_detail, Detail, get_detail, patch_detail, post_detail, put_detail};
+    use crate::util::ops::PatchOperator;
+    use crate::util::testing::TestApp;
+
+    fn detail(cid: &str, open_by: &str, credit: &str) -> Detail {
+        Detail {
+            cid: cid.to_string(),
+            name: "离散数学".to_string(),
+            english_name: "Discrete Mathematics".to_string(),
+            open_by: open_by.to_string(),
+            credit: credit.to_string(),
+            detail: String::new(),
+            offered: vec![],
+        }
+    }
+
+    #[async_test]
+    async fn test_detail_crud_syncs_course() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        post_course(db, &Course {
+            cid: "MA201".to_string(),
+            name: "旧名".to_string(),
+            taught_by: vec![vec!["Alice".to_string()], vec!["Bob".to_string()]],
+            faculty: "旧院系".to_string(),
+        }).await.unwrap();
+        let courses = || async { get_course(app.db(), Some(doc! {"cid": "MA201"})).await.unwrap() };
+
+        post_detail(db, &detail("MA201", "数学系", "3")).await.unwrap();
+        assert!(post_detail(db, &detail("MA201", "数学系", "3")).await.is_err());
+        assert!(post_detail(db, &detail("MA202", "数学系", "three")).await.is_err());
+        assert_eq!(courses().await[0].name, "离散数学");
+        assert_eq!(courses().await[0].faculty, "数学系");
+
+        assert!(put_detail(db, "MA201", &detail("MA999", "数学系", "3")).await.is_err());
+        put_detail(db, "MA201", &detail("MA201", "统计系", "4")).await.unwrap();
+        assert_eq!(courses().await[0].faculty, "统计系");
+
+        let set = |field: &str, value: &str| PatchOperator::Set(field.to_string(), Bson::String(value.to_string()));
+        assert!(patch_detail(db, "MA201", set("credit", "abc")).await.is_err());
+        assert!(patch_detail(db, "MA201", set("open_by", "")).await.is_err());
+        assert!(patch_detail(db, "MA201", PatchOperator::Set("credit".to_string(), Bson::Int32(3))).await.is_err());
+        assert_eq!(patch_detail(db, "MA201", set("english_name", "")).await.unwrap(), 1);
+        assert_eq!(patch_detail(db, "MA201", set("open_by", "数学系")).await.unwrap(), 1);
+        assert_eq!(courses().await[0].faculty, "数学系");
+        assert_eq!(courses().await[0].taught_by.len(), 2);
+
+        // 删除 Detail 不影响课程
+        assert_eq!(delete_detail(db, "MA201").await.unwrap(), 1);
+        assert!(delete_detail(db, "MA201").await.is_err());
+        assert!(get_detail(app.db(), Some(doc! {"cid": "MA201"})).await.unwrap().is_empty());
+        assert_eq!(courses().await.len(), 1);
+    }
+}
@@ -0,0 +1,319 @@
+use std::error::Error;
+use std::time::Duration;
+
+use actix_web::{Responder, web};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use chrono::Utc;
+use futures::stream::StreamExt;
+use mongodb::bson::{self, Bson, doc, from_bson, to_bson};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::json_response;
+use crate::resources::session::get_admin_session;
+use crate::util::database::Database;
+use crate::util::email_sender::{Email, EmailSender};
+use crate::util::email_template::{Language, Template};
+use crate::util::index::IndexSpec;
+use crate::util::metrics::{inc_email, timed};
+use crate::util::state::AppState;
+
+const MAX_ATTEMPTS: i32 = 6;
+const BASE_DELAY: i64 = 30;
+const MAX_DELAY: i64 = 60 * 60;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const DEAD_LETTER_LIMIT: i64 = 50;
+// 认领后超过这个时间（秒）仍是 sending，才认为认领它的进程已经退出
+const SENDING_LEASE: i64 = 10 * 60;
+// sent / dead 的记录保留七天后由 TTL 索引删除
+pub(crate) const FINISHED_TTL: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailStatus {
+    Pending,
+    Sending,
+    Sent,
+    Dead,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedEmail {
+    pub id: String,
+    pub email: Email,
+    pub status: MailStatus,
+    pub attempts: i32,
+    pub next_attempt_at: i64,
+    #[serde(default)]
+    pub locked_at: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    // 变为 sent / dead 的时间，TTL 索引据此删除
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<bson::DateTime>,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct QueueReport {
+    pending: i64,
+    sending: i64,
+    sent: i64,
+    dead: i64,
+    dead_letters: Vec<QueuedEmail>,
+}
+
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![
+        IndexSpec::new("EmailQueue", doc! {"id": 1}).unique(),
+        IndexSpec::new("EmailQueue", doc! {"status": 1, "next_attempt_at": 1}),
+        IndexSpec::new("EmailQueue", doc! {"finished_at": 1}).expire_after(FINISHED_TTL),
+    ]
+}
+
+// 第 n 次失败后等待 30s * 2^(n-1)，最长一小时
+pub fn backoff(attempts: i32) -> i64 {
+    let exp = (attempts.max(1) - 1).min(16) as u32;
+    (BASE_DELAY * 2i64.pow(exp)).min(MAX_DELAY)
+}
+
+pub async fn enqueue(db: &Database, email: Email) -> Result<String, Box<dyn Error>> {
+    let queued = QueuedEmail {
+        id: Uuid::new_v4().to_string(),
+        email,
+        status: MailStatus::Pending,
+        attempts: 0,
+        next_attempt_at: Utc::now().timestamp(),
+        locked_at: None,
+        last_error: None,
+        created_at: Utc::now().to_rfc2822(),
+        finished_at: None,
+    };
+    let document = to_bson(&queued)?.as_document().ok_or("unexpected error when encode email")?.clone();
+    db.cli.database(&db.name).collection("EmailQueue").insert_one(document, None).await?;
+    Ok(queued.id)
+}
+
+pub async fn enqueue_template(db: &Database, sender: &EmailSender, recv_addr: &str, template: Template, lang: Language, vars: &[(&str, &str)]) -> Result<String, Box<dyn Error>> {
+    enqueue(db, sender.render(recv_addr, template, lang, vars)).await
+}
+
+async fn claim(db: &Database) -> Result<Option<QueuedEmail>, Box<dyn Error>> {
+    let claimed = db.cli
+        .database(&db.name)
+        .collection("EmailQueue")
+        .find_one_and_update(
+            doc! {"status": "pending", "next_attempt_at": {"$lte": Utc::now().timestamp()}},
+            doc! {"$set": {"status": "sending", "locked_at": Utc::now().timestamp()}},
+            FindOneAndUpdateOptions::builder().sort(doc! {"next_attempt_at": 1}).build(),
+        )
+        .await?;
+    match claimed {
+        Some(document) => Ok(Some(from_bson::<QueuedEmail>(Bson::Document(document))?)),
+        None => Ok(None),
+    }
+}
+
+// 处理所有到期的邮件，返回本轮处理的数量
+pub async fn process_due(db: &Database, sender: &EmailSender) -> Result<usize, Box<dyn Error>> {
+    let queue = db.cli.database(&db.name).collection("EmailQueue");
+    let mut processed = 0;
+    while let Some(queued) = claim(db).await? {
+        let result = sender.send_blocking(queued.email.clone()).await;
+        inc_email(result.is_ok());
+        // 正文里有验证码等敏感内容，发出后就清掉，不在库里长期保存
+        let update = match result {
+            Ok(_) => doc! {
+                "$set": {"status": "sent", "last_error": Bson::Null, "email.body": "", "finished_at": Utc::now()},
+                "$unset": {"email.html": ""},
+                "$inc": {"attempts": 1},
+            },
+            Err(err) => {
+                let attempts = queued.attempts + 1;
+                log::warn!("sending email {} to {} failed ({} attempts): {}", queued.id, queued.email.to, attempts, err);
+                let mut set = doc! {
+                    "status": "pending",
+                    "attempts": attempts,
+                    "next_attempt_at": Utc::now().timestamp() + backoff(attempts),
+                    "last_error": err.to_string(),
+                };
+                if attempts >= MAX_ATTEMPTS {
+                    set.insert("status", "dead");
+                    set.insert("finished_at", Utc::now());
+                }
+                doc! {"$set": set}
+            }
+        };
+        queue.update_one(doc! {"id": &queued.id}, update, None).await?;
+        processed += 1;
+    }
+    Ok(processed)
+}
+
+// 进程在发送途中退出时，邮件会停留在 sending；租约过期后放回队列，
+// 其他实例正在发送的邮件不受影响。没有 locked_at 的是旧版本留下的记录
+pub async fn recover(db: &Database) -> Result<i64, Box<dyn Error>> {
+    let expired = Utc::now().timestamp() - SENDING_LEASE;
+    Ok(db.cli
+        .database(&db.name)
+        .collection("EmailQueue")
+        .update_many(
+            doc! {"status": "sending", "$or": [{"locked_at": {"$lt": expired}}, {"locked_at": null}]},
+            doc! {"$set": {"status": "pending"}},
+            None,
+        )
+        .await?
+        .modified_count)
+}
+
+pub fn spawn_worker(state: web::Data<AppState>) {
+    actix_rt::spawn(async move {
+        loop {
+            if let Err(err) = recover(&state.db).await {
+                log::error!("email queue recover failed: {}", err);
+            }
+            if let Err(err) = process_due(&state.db, &state.email_sender).await {
+                log::error!("email queue worker failed: {}", err);
+            }
+            actix_rt::time::delay_for(POLL_INTERVAL).await;
+        }
+    });
+}
+
+pub async fn get_queue_report(db: &Database) -> Result<QueueReport, Box<dyn Error>> {
+    let queue = db.cli.database(&db.name).collection("EmailQueue");
+    let mut report = QueueReport::default();
+    report.pending = queue.count_documents(doc! {"status": "pending"}, None).await?;
+    report.sending = queue.count_documents(doc! {"status": "sending"}, None).await?;
+    report.sent = queue.count_documents(doc! {"status": "sent"}, None).await?;
+    report.dead = queue.count_documents(doc! {"status": "dead"}, None).await?;
+    report.dead_letters = queue
+        .find(doc! {"status": "dead"}, FindOptions::builder().sort(doc! {"next_attempt_at": -1}).limit(DEAD_LETTER_LIMIT).build())
+        .await?
+        // 死信要留着正文以便重试，但报告里不返回正文
+        .filter_map(|document| async move {
+            let mut queued = from_bson::<QueuedEmail>(Bson::Document(document.ok()?)).ok()?;
+            queued.email.body = String::new();
+            queued.email.html = None;
+            Some(queued)
+        })
+        .collect()
+        .await;
+    Ok(report)
+}
+
+pub async fn retry_dead(db: &Database, id: &str) -> Result<i64, Box<dyn Error>> {
+    let modified = db.cli
+        .database(&db.name)
+        .collection("EmailQueue")
+        .update_one(
+            doc! {"id": id, "status": "dead"},
+            doc! {
+                "$set": {"status": "pending", "attempts": 0, "last_error": Bson::Null, "next_attempt_at": Utc::now().timestamp()},
+                "$unset": {"finished_at": ""},
+            },
+            None,
+        )
+        .await?
+        .modified_count;
+    if modified == 0 {
+        return Err("dead letter not found".into());
+    }
+    Ok(modified)
+}
+
+async fn get_queue_handler(state: web::Data<AppState>, auth: BearerAuth) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("get_queue_report", get_queue_report(&state.db)).await))
+}
+
+async fn retry_dead_handler(state: web::Data<AppState>, auth: BearerAuth, id: web::Path<String>) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("retry_dead", retry_dead(&state.db, &id)).await))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/admin/email_queue")
+            .route(web::get().to(get_queue_handler))
+    );
+    cfg.service(
+        web::resource("/admin/email_queue/{id}/retry")
+            .route(web::post().to(retry_dead_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+    use futures_await_test::async_test;
+    use mongodb::bson::doc;
+
+    use crate::resources::email_queue::{backoff, enqueue, process_due, recover, retry_dead, SENDING_LEASE};
+    use crate::util::email_sender::{Email, EmailSender, MemoryEmailTransport};
+    use crate::util::testing::TestApp;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), 30);
+        assert_eq!(backoff(2), 60);
+        assert_eq!(backoff(5), 480);
+        assert_eq!(backoff(100), 3600);
+    }
+
+    #[async_test]
+    async fn test_enqueue_and_process() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        let sender = EmailSender::with_transport("flow@localhost", Arc::new(MemoryEmailTransport::default()));
+        let id = enqueue(db, Email {
+            to: "11712009@mail.sustech.edu.cn".to_string(),
+            subject: "test".to_string(),
+            body: "test".to_string(),
+            html: None,
+        }).await.unwrap();
+        assert_eq!(process_due(db, &sender).await.unwrap(), 1);
+        assert!(sender.sent().iter().any(|email| email.subject == "test"));
+        let queue = db.cli.database(&db.name).collection("EmailQueue");
+        let queued = queue.find_one(doc! {"id": &id}, None).await.unwrap().unwrap();
+        assert_eq!(queued.get_str("status").unwrap(), "sent");
+        assert_eq!(queued.get_document("email").unwrap().get_str("body").unwrap(), "");
+        assert!(queued.get_datetime("finished_at").is_ok());
+    }
+
+    #[async_test]
+    async fn test_recover_only_expired_leases() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        let queue = db.cli.database(&db.name).collection("EmailQueue");
+        let now = Utc::now().timestamp();
+        for (id, locked_at) in &[("fresh", now), ("expired", now - SENDING_LEASE - 1)] {
+            queue.insert_one(doc! {"id": *id, "status": "sending", "locked_at": *locked_at}, None).await.unwrap();
+        }
+        queue.insert_one(doc! {"id": "legacy", "status": "sending"}, None).await.unwrap();
+        assert_eq!(recover(db).await.unwrap(), 2);
+        let status = |id: &'static str| {
+            let queue = queue.clone();
+            async move { queue.find_one(doc! {"id": id}, None).await.unwrap().unwrap().get_str("status").unwrap().to_string() }
+        };
+        assert_eq!(status("fresh").await, "sending");
+        assert_eq!(status("expired").await, "pending");
+        assert_eq!(status("legacy").await, "pending");
+    }
+
+    #[async_test]
+    async fn test_retry_dead_clears_last_error() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        let queue = db.cli.database(&db.name).collection("EmailQueue");
+        queue.insert_one(doc! {"id": "dead", "status": "dead", "attempts": 8, "last_error": "timeout"}, None).await.unwrap();
+        assert_eq!(retry_dead(db, "dead").await.unwrap(), 1);
+        let queued = queue.find_one(doc! {"id": "dead"}, None).await.unwrap().unwrap();
+        assert_eq!(queued.get_str("status").unwrap(), "pending");
+        assert_eq!(queued.get_i32("attempts").unwrap(), 0);
+        assert!(queued.is_null("last_error"));
+        assert!(retry_dead(db, "dead").await.is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use std::error::Error;
+
+use actix_web::{Responder, web};
+use futures::future;
+use futures::stream::StreamExt;
+use mongodb::bson::{Bson, doc, Document, from_bson};
+use serde::{Deserialize, Serialize};
+
+use crate::json_response;
+use crate::util::database::Database;
+use crate::util::metrics::timed;
+use crate::util::state::AppState;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Faculty {
+    faculty: String,
+    courses: u32,
+    likes: Option<f32>,
+    useful: Option<f32>,
+    easy: Option<f32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CatalogCourse {
+    cid: String,
+    name: String,
+    credit: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CatalogFaculty {
+    faculty: String,
+    courses: Vec<CatalogCourse>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FacultyQuery {
+    faculty: Option<String>,
+}
+
+impl FacultyQuery {
+    fn as_match(&self) -> Document {
+        match &self.faculty {
+            Some(faculty) => doc! { "$match": {"faculty": faculty} },
+            None => doc! { "$match": {} },
+        }
+    }
+}
+
+// 课程的院系以 Detail.open_by 为准，没有 Detail 时用 Course.faculty；
+// 每门课程先对自己的 Rate 取平均，院系再对课程取平均，课程数不受 Rate 条数影响
+pub async fn get_faculty(db: &Database, query: &FacultyQuery) -> Result<Vec<Faculty>, Box<dyn Error>> {
+    let pipeline = vec![
+        doc! { "$group": {"_id": "$cid", "faculty": {"$first": "$faculty"}} },
+        doc! { "$lookup": {"from": "Detail", "localField": "_id", "foreignField": "cid", "as": "detail"} },
+        doc! { "$addFields": {"faculty": {"$ifNull": [{"$arrayElemAt": ["$detail.open_by", 0]}, "$faculty"]}} },
+        query.as_match(),
+        doc! { "$lookup": {"from": "Rate", "localField": "_id", "foreignField": "cid", "as": "rate"} },
+        doc! {
+            "$addFields": {
+                "likes": {"$avg": "$rate.likes"},
+                "useful": {"$avg": "$rate.useful"},
+                "easy": {"$avg": "$rate.easy"},
+            }
+        },
+        doc! {
+            "$group": {
+                "_id": "$faculty",
+                "faculty": {"$first": "$faculty"},
+                "courses": {"$sum": 1},
+                "likes": {"$avg": "$likes"},
+                "useful": {"$avg": "$useful"},
+                "easy": {"$avg": "$easy"},
+            }
+        },
+        doc! { "$sort": {"_id": 1} },
+    ];
+    Ok(db
+        .cli
+        .database(&db.name)
+        .collection("Course")
+        .aggregate(pipeline, None)
+        .await?
+        .map(|d| {
+            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|d| {
+            from_bson::<Faculty>(d.unwrap())
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|x| x.unwrap())
+        .collect::<Vec<Faculty>>()
+        .await
+    )
+}
+
+// 院系的取法和 get_faculty 一致
+pub async fn get_catalog(db: &Database, query: &FacultyQuery) -> Result<Vec<CatalogFaculty>, Box<dyn Error>> {
+    let pipeline = vec![
+        doc! {
+            "$group": {
+                "_id": "$cid",
+                "cid": {"$first": "$cid"},
+                "name": {"$first": "$name"},
+                "faculty": {"$first": "$faculty"},
+            }
+        },
+        doc! { "$lookup": {"from": "Detail", "localField": "cid", "foreignField": "cid", "as": "detail"} },
+        doc! {
+            "$addFields": {
+                "faculty": {"$ifNull": [{"$arrayElemAt": ["$detail.open_by", 0]}, "$faculty"]},
+                "credit": {"$arrayElemAt": ["$detail.credit", 0]},
+            }
+        },
+        query.as_match(),
+        doc! { "$sort": {"cid": 1} },
+        doc! {
+            "$group": {
+                "_id": "$faculty",
+                "faculty": {"$first": "$faculty"},
+                "courses": {"$push": {"cid": "$cid", "name": "$name", "credit": "$credit"}},
+            }
+        },
+        doc! { "$sort": {"_id": 1} },
+    ];
+    Ok(db
+        .cli
+        .database(&db.name)
+        .collection("Course")
+        .aggregate(pipeline, None)
+        .await?
+        .map(|d| {
+            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|d| {
+            from_bson::<CatalogFaculty>(d.unwrap())
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|x| x.unwrap())
+        .collect::<Vec<CatalogFaculty>>()
+        .await
+    )
+}
+
+async fn get_faculty_handler(state: web::Data<AppState>, req: web::Query<FacultyQuery>) -> impl Responder {
+    web::Json(json_response!(timed("get_faculty", get_faculty(&state.db, &req)).await))
+}
+
+async fn get_catalog_handler(state: web::Data<AppState>, req: web::Query<FacultyQuery>) -> impl Responder {
+    web::Json(json_response!(timed("get_catalog", get_catalog(&state.db, &req)).await))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/faculty")
+            .route(web::get().to(get_faculty_handler))
+    );
+    cfg.service(
+        web::resource("/faculty/catalog")
+            .route(web::get().to(get_catalog_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use futures_await_test::async_test;
+    use mongodb::bson::doc;
+
+    use crate::resources::faculty::{FacultyQuery, get_catalog, get_faculty};
+    use crate::util::testing::TestApp;
+
+    // 在公共数据上再给 MA101 加一条 Rate，把 CS102 的 Detail 登记为软件系开课
+    async fn seed(app: &TestApp) {
+        app.seed_catalog().await.unwrap();
+        let database = app.db().cli.database(&app.db().name);
+        database
+            .collection("Rate")
+            .insert_one(doc! {"cid": "MA101", "name": "数学分析", "ratings": 1.0, "likes": 1.0, "useful": 1.0, "easy": 1.0}, None)
+            .await
+            .unwrap();
+        database
+            .collection("Detail")
+            .insert_one(doc! {"cid": "CS102", "name": "程序设计", "english_name": "", "open_by": "软件系", "credit": "2", "detail": ""}, None)
+            .await
+            .unwrap();
+    }
+
+    #[async_test]
+    async fn test_get_faculty() {
+        let app = TestApp::new().await.unwrap();
+        seed(&app).await;
+        let faculties = get_faculty(app.db(), &FacultyQuery { faculty: None }).await.unwrap();
+        assert_eq!(faculties.iter().map(|f| f.faculty.as_str()).collect::<Vec<&str>>(), vec!["数学系", "计算机系", "软件系"]);
+        let cs = faculties.iter().find(|f| f.faculty == "计算机系").unwrap();
+        // CS101 有两个教学班，只算一门课
+        assert_eq!(cs.courses, 1);
+        assert_eq!(cs.likes, Some(5.0));
+        assert_eq!(cs.easy, Some(3.0));
+        // 按 Detail.open_by 归到软件系，没有 Rate 的课程不参与平均
+        let se = faculties.iter().find(|f| f.faculty == "软件系").unwrap();
+        assert_eq!(se.courses, 1);
+        assert_eq!(se.likes, None);
+
+        // MA101 有两条 Rate：课程数仍是 1，评分取两条的平均
+        let math = get_faculty(app.db(), &FacultyQuery { faculty: Some("数学系".to_string()) }).await.unwrap();
+        assert_eq!(math.len(), 1);
+        assert_eq!(math[0].courses, 1);
+        assert_eq!(math[0].likes, Some(2.0));
+        assert_eq!(math[0].useful, Some(3.0));
+        assert!(get_faculty(app.db(), &FacultyQuery { faculty: Some("物理系".to_string()) }).await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn test_get_catalog() {
+        let app = TestApp::new().await.unwrap();
+        seed(&app).await;
+        let catalog = get_catalog(app.db(), &FacultyQuery { faculty: Some("计算机系".to_string()) }).await.unwrap();
+        assert_eq!(catalog.len(), 1);
+        let courses = &catalog[0].courses;
+        assert_eq!(courses.iter().map(|c| c.cid.as_str()).collect::<Vec<&str>>(), vec!["CS101"]);
+        assert_eq!(courses[0].credit, Some("3".to_string()));
+        let catalog = get_catalog(app.db(), &FacultyQuery { faculty: Some("软件系".to_string()) }).await.unwrap();
+        assert_eq!(catalog[0].courses[0].cid, "CS102");
+        assert_eq!(catalog[0].courses[0].credit, Some("2".to_string()));
+        assert_eq!(get_catalog(app.db(), &FacultyQuery { faculty: None }).await.unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,423 @@
+use core::fmt;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+
+use actix_web::{Responder, web};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use bytes::Bytes;
+use mongodb::bson::{Bson, doc, to_bson};
+use mongodb::options::UpdateOptions;
+use serde::{Deserialize, Serialize};
+
+use crate::json_response;
+use crate::resources::course::{CatalogError, Course, get_course};
+use crate::resources::detail::{Detail, get_detail};
+use crate::resources::session::get_admin_session;
+use crate::util::database::Database;
+use crate::util::metrics::timed;
+use crate::util::state::AppState;
+
+const BATCH_SIZE: usize = 100;
+const UPLOAD_LIMIT: usize = 8 * 1024 * 1024;
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    Csv,
+    Json,
+}
+
+impl ImportFormat {
+    pub fn parse(format: &str) -> Result<ImportFormat, ImportError> {
+        match format.to_lowercase().as_str() {
+            "csv" => Ok(ImportFormat::Csv),
+            "json" => Ok(ImportFormat::Json),
+            _ => Err(ImportError::UnknownFormat(format.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    UnknownFormat(String),
+    Invalid(String, CatalogError),
+    Conflict(String),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::UnknownFormat(format) => write!(f, "unknown import format {}, expect csv or json", format),
+            ImportError::Invalid(cid, err) => write!(f, "course {} is invalid: {}", cid, err),
+            ImportError::Conflict(cid) => write!(f, "rows of course {} disagree on name, english_name, faculty, credit or detail", cid),
+        }
+    }
+}
+
+impl Error for ImportError {}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CatalogEntry {
+    cid: String,
+    name: String,
+    #[serde(default)]
+    english_name: String,
+    faculty: String,
+    credit: String,
+    #[serde(default)]
+    detail: String,
+    #[serde(default)]
+    taught_by: Vec<Vec<String>>,
+}
+
+// 教务系统导出的 CSV 每行是一个教学班，instructors 用 ; 或 、 分隔
+#[derive(Debug, Deserialize)]
+struct CsvRow {
+    cid: String,
+    name: String,
+    #[serde(default)]
+    english_name: String,
+    faculty: String,
+    credit: String,
+    #[serde(default)]
+    detail: String,
+    #[serde(default)]
+    instructors: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangedEntry {
+    cid: String,
+    fields: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct ImportReport {
+    dry_run: bool,
+    added: Vec<String>,
+    changed: Vec<ChangedEntry>,
+    unchanged: usize,
+    written: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportQuery {
+    format: ImportFormat,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+impl CatalogEntry {
+    fn course(&self) -> Course {
+        Course {
+            cid: self.cid.clone(),
+            name: self.name.clone(),
+            taught_by: self.taught_by.clone(),
+            faculty: self.faculty.clone(),
+        }
+    }
+
+    fn detail(&self) -> Detail {
+        Detail {
+            cid: self.cid.clone(),
+            name: self.name.clone(),
+            english_name: self.english_name.clone(),
+            open_by: self.faculty.clone(),
+            credit: self.credit.clone(),
+            detail: self.detail.clone(),
+            offered: vec![],
+        }
+    }
+
+    fn validate(&self) -> Result<(), ImportError> {
+        self.course().validate()
+            .and_then(|_| self.detail().validate())
+            .map_err(|err| ImportError::Invalid(self.cid.clone(), err))
+    }
+}
+
+fn sorted_sections(taught_by: &[Vec<String>]) -> Vec<Vec<String>> {
+    let mut sections = taught_by
+        .iter()
+        .map(|section| {
+            let mut section = section.clone();
+            section.sort();
+            section
+        })
+        .collect::<Vec<Vec<String>>>();
+    sections.sort();
+    sections.dedup();
+    sections
+}
+
+impl CsvRow {
+    fn entry(self) -> CatalogEntry {
+        let section = self.instructors
+            .split(|c| c == ';' || c == '、')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<String>>();
+        CatalogEntry {
+            cid: self.cid,
+            name: self.name,
+            english_name: self.english_name,
+            faculty: self.faculty,
+            credit: self.credit,
+            detail: self.detail,
+            taught_by: if section.is_empty() { vec![] } else { vec![section] },
+        }
+    }
+}
+
+fn trim_entry(mut entry: CatalogEntry) -> CatalogEntry {
+    for field in [&mut entry.cid, &mut entry.name, &mut entry.english_name, &mut entry.faculty, &mut entry.credit, &mut entry.detail].iter_mut() {
+        **field = field.trim().to_string();
+    }
+    entry.taught_by = entry.taught_by
+        .into_iter()
+        .map(|section| section.into_iter().map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect())
+        .filter(|section: &Vec<String>| !section.is_empty())
+        .collect();
+    entry
+}
+
+// 同一课程号的多行合并教学班，教学班以外的字段不一致视为冲突
+fn merge_entries(rows: Vec<CatalogEntry>) -> Result<Vec<CatalogEntry>, ImportError> {
+    let mut entries = Vec::<CatalogEntry>::new();
+    let mut index = HashMap::<String, usize>::new();
+    for row in rows.into_iter().map(trim_entry) {
+        match index.get(&row.cid) {
+            Some(&i) => {
+                let entry = &mut entries[i];
+                if entry.name != row.name || entry.english_name != row.english_name || entry.faculty != row.faculty
+                    || entry.credit != row.credit || entry.detail != row.detail {
+                    return Err(ImportError::Conflict(row.cid));
+                }
+                entry.taught_by.extend(row.taught_by);
+            }
+            None => {
+                index.insert(row.cid.clone(), entries.len());
+                entries.push(row);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub fn parse_catalog(data: &[u8], format: ImportFormat) -> Result<Vec<CatalogEntry>, Box<dyn Error>> {
+    let rows = match format {
+        ImportFormat::Json => serde_json::from_slice::<Vec<CatalogEntry>>(data)?,
+        ImportFormat::Csv => csv::Reader::from_reader(data)
+            .deserialize::<CsvRow>()
+            .map(|row| row.map(CsvRow::entry))
+            .collect::<Result<Vec<CatalogEntry>, csv::Error>>()?,
+    };
+    Ok(merge_entries(rows)?)
+}
+
+async fn diff_catalog(db: &Database, entries: &[CatalogEntry]) -> Result<(ImportReport, Vec<usize>), Box<dyn Error>> {
+    let cids = entries.iter().map(|e| Bson::String(e.cid.clone())).collect::<Vec<Bson>>();
+    let courses = get_course(db, Some(doc! {"cid": {"$in": cids.clone()}}))
+        .await?
+        .into_iter()
+        .map(|c| (c.cid.clone(), c))
+        .collect::<HashMap<String, Course>>();
+    let details = get_detail(db, Some(doc! {"cid": {"$in": cids}}))
+        .await?
+        .into_iter()
+        .map(|d| (d.cid.clone(), d))
+        .collect::<HashMap<String, Detail>>();
+
+    let mut report = ImportReport::default();
+    let mut pending = vec![];
+    for (i, entry) in entries.iter().enumerate() {
+        let (course, detail) = (courses.get(&entry.cid), details.get(&entry.cid));
+        if course.is_none() && detail.is_none() {
+            report.added.push(entry.cid.clone());
+            pending.push(i);
+            continue;
+        }
+        let mut fields = vec![];
+        match course {
+            Some(course) => {
+                if course.name != entry.name { fields.push("name") }
+                if course.faculty != entry.faculty { fields.push("faculty") }
+                if sorted_sections(&course.taught_by) != sorted_sections(&entry.taught_by) { fields.push("taught_by") }
+            }
+            None => fields.push("course"),
+        }
+        match detail {
+            Some(detail) => {
+                if detail.english_name != entry.english_name { fields.push("english_name") }
+                if detail.credit != entry.credit { fields.push("credit") }
+                if detail.detail != entry.detail { fields.push("detail") }
+            }
+            None => fields.push("detail"),
+        }
+        if fields.is_empty() {
+            report.unchanged += 1;
+        } else {
+            report.changed.push(ChangedEntry { cid: entry.cid.clone(), fields });
+            pending.push(i);
+        }
+    }
+    Ok((report, pending))
+}
+
+async fn write_batch(db: &Database, entries: &[&CatalogEntry]) -> Result<(), Box<dyn Error>> {
+    let database = db.cli.database(&db.name);
+    let cids = entries.iter().map(|e| Bson::String(e.cid.clone())).collect::<Vec<Bson>>();
+    let mut sections = vec![];
+    for entry in entries {
+        for section in entry.course().sections() {
+            sections.push(to_bson(&section)?.as_document().ok_or("failed to transfer Bson to Document")?.clone());
+        }
+    }
+    // 先插入新教学班再删旧的，中途失败时课程不会整个消失，最多暂时多出旧教学班
+    let inserted = database
+        .collection("Course")
+        .insert_many(sections, None)
+        .await?
+        .inserted_ids
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect::<Vec<Bson>>();
+    database
+        .collection("Course")
+        .delete_many(doc! {"cid": {"$in": cids}, "_id": {"$nin": inserted}}, None)
+        .await?;
+    // 导入数据里没有开课学期，用 $set 保留 Detail 里已登记的 offered
+    for entry in entries {
+        let detail = to_bson(&entry.detail())?.as_document().ok_or("failed to transfer Bson to Document")?.clone();
+        database
+            .collection("Detail")
+            .update_one(doc! {"cid": &entry.cid}, doc! {"$set": detail}, UpdateOptions::builder().upsert(true).build())
+            .await?;
+    }
+    Ok(())
+}
+
+pub async fn import_catalog(db: &Database, entries: &[CatalogEntry], dry_run: bool) -> Result<ImportReport, Box<dyn Error>> {
+    for entry in entries {
+        entry.validate()?;
+    }
+    let (mut report, pending) = diff_catalog(db, entries).await?;
+    report.dry_run = dry_run;
+    if dry_run {
+        return Ok(report);
+    }
+    let pending = pending.into_iter().map(|i| &entries[i]).collect::<Vec<&CatalogEntry>>();
+    for batch in pending.chunks(BATCH_SIZE) {
+        write_batch(db, batch).await?;
+        report.written += batch.len();
+    }
+    Ok(report)
+}
+
+fn usage() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "usage: server_v2 import <file> [--format csv|json] [--dry-run]")
+}
+
+pub async fn run_command(db: &Database, args: &[String]) -> io::Result<()> {
+    let mut path = None;
+    let mut format = None;
+    let mut dry_run = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--format" => format = Some(args.next().ok_or_else(usage)?.clone()),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(usage()),
+        }
+    }
+    let path = path.ok_or_else(usage)?;
+    let format = format.unwrap_or_else(|| path.rsplit('.').next().unwrap_or("").to_string());
+    let to_io = |e: Box<dyn Error>| io::Error::new(io::ErrorKind::Other, e.to_string());
+
+    let format = ImportFormat::parse(&format).map_err(|e| to_io(Box::new(e)))?;
+    let entries = parse_catalog(&std::fs::read(&path)?, format).map_err(to_io)?;
+    let report = import_catalog(db, &entries, dry_run).await.map_err(to_io)?;
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
+
+async fn post_import_handler(state: web::Data<AppState>, auth: BearerAuth, req: web::Query<ImportQuery>, body: Bytes) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    let entries = json_response!(parse_catalog(&body, req.format)).data.unwrap();
+    web::Json(json_response!(timed("import_catalog", import_catalog(&state.db, &entries, req.dry_run)).await))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/admin/import")
+            .data(web::PayloadConfig::new(UPLOAD_LIMIT))
+            .route(web::post().to(post_import_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use crate::resources::import::{ImportFormat, parse_catalog};
+
+    #[test]
+    fn test_parse_csv_merges_sections() {
+        let csv = "cid,name,english_name,faculty,credit,detail,instructors\n\
+                   CS101,计算机导论,Intro to CS,计算机系,3,,张三;李四\n\
+                   CS101,计算机导论,Intro to CS,计算机系,3,,王五\n\
+                   MA101,数学分析,,数学系,5,,\n";
+        let entries = parse_catalog(csv.as_bytes(), ImportFormat::Csv).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].taught_by, vec![vec!["张三".to_string(), "李四".to_string()], vec!["王五".to_string()]]);
+        assert!(entries[1].taught_by.is_empty());
+    }
+
+    #[test]
+    fn test_parse_csv_rejects_conflicting_rows() {
+        let csv = "cid,name,english_name,faculty,credit,detail,instructors\n\
+                   CS101,计算机导论,,计算机系,3,,张三\n\
+                   CS101,计算机导论,,数学系,3,,李四\n";
+        assert!(parse_catalog(csv.as_bytes(), ImportFormat::Csv).is_err());
+
+        let csv = "cid,name,english_name,faculty,credit,detail,instructors\n\
+                   CS101,计算机导论,Intro to CS,计算机系,3,,张三\n\
+                   CS101,计算机导论,Introduction,计算机系,3,,李四\n";
+        assert!(parse_catalog(csv.as_bytes(), ImportFormat::Csv).is_err());
+        let csv = "cid,name,english_name,faculty,credit,detail,instructors\n\
+                   CS101,计算机导论,,计算机系,3,入门课,张三\n\
+                   CS101,计算机导论,,计算机系,3,,李四\n";
+        assert!(parse_catalog(csv.as_bytes(), ImportFormat::Csv).is_err());
+    }
+
+    #[test]
+    fn test_parse_csv_trims_fields() {
+        let csv = "cid,name,english_name,faculty,credit,detail,instructors\n\
+                   CS101 , 计算机导论,Intro to CS ,计算机系 , 3,,张三\n\
+                   CS101,计算机导论 ,Intro to CS,计算机系,3 ,, 李四 \n";
+        let entries = parse_catalog(csv.as_bytes(), ImportFormat::Csv).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].cid, "CS101");
+        assert_eq!(entries[0].name, "计算机导论");
+        assert_eq!(entries[0].english_name, "Intro to CS");
+        assert_eq!(entries[0].credit, "3");
+        assert_eq!(entries[0].taught_by, vec![vec!["张三".to_string()], vec!["李四".to_string()]]);
+    }
+
+    #[test]
+    fn test_parse_json_merges_and_rejects_conflicts() {
+        let json = r#"[
+            {"cid": "CS101", "name": "计算机导论", "faculty": "计算机系", "credit": "3", "taught_by": [["张三"]]},
+            {"cid": " CS101", "name": "计算机导论", "faculty": "计算机系", "credit": "3", "taught_by": [["李四", " "]]}
+        ]"#;
+        let entries = parse_catalog(json.as_bytes(), ImportFormat::Json).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].taught_by, vec![vec!["张三".to_string()], vec!["李四".to_string()]]);
+
+        let json = r#"[
+            {"cid": "CS101", "name": "计算机导论", "faculty": "计算机系", "credit": "3"},
+            {"cid": "CS101", "name": "计算机导论", "faculty": "计算机系", "credit": "4"}
+        ]"#;
+        assert!(parse_catalog(json.as_bytes(), ImportFormat::Json).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::error::Error;
+
+use actix_web::{Responder, web};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use chrono::{Duration, Utc};
+use futures::stream::StreamExt;
+use mongodb::bson::{Bson, doc, from_bson, to_bson};
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::json_response;
+use crate::resources::register_link::{RegisterError, validate_address};
+use crate::resources::session::get_admin_session;
+use crate::util::database::Database;
+use crate::util::index::IndexSpec;
+use crate::util::metrics::timed;
+use crate::util::state::AppState;
+
+const DEFAULT_EXPIRE_DAYS: i64 = 7;
+const MAX_EXPIRE_DAYS: i64 = 365;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub code: String,
+    pub email: Option<String>,
+    pub max_uses: i32,
+    pub used: i32,
+    pub expires_at: i64,
+    pub created_by: String,
+    pub created_at: String,
+    pub redeemed_by: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewInvite {
+    pub email: Option<String>,
+    pub max_uses: Option<i32>,
+    pub expire_days: Option<i64>,
+}
+
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![IndexSpec::new("Invite", doc! {"code": 1}).unique()]
+}
+
+pub async fn post_invite(db: &Database, created_by: &str, new_invite: NewInvite) -> Result<Invite, Box<dyn Error>> {
+    let max_uses = new_invite.max_uses.unwrap_or(1);
+    if max_uses < 1 {
+        return Err("max_uses must be at least 1".into());
+    }
+    let expire_days = new_invite.expire_days.unwrap_or(DEFAULT_EXPIRE_DAYS);
+    if expire_days < 1 || expire_days > MAX_EXPIRE_DAYS {
+        return Err(format!("expire_days must be between 1 and {}", MAX_EXPIRE_DAYS).into());
+    }
+    let expires_at = Utc::now()
+        .checked_add_signed(Duration::days(expire_days))
+        .ok_or("expire_days out of range")?;
+    let email = match new_invite.email {
+        Some(email) => Some(validate_address(email.trim())?.to_lowercase()),
+        None => None,
+    };
+    let invite = Invite {
+        code: Uuid::new_v4().to_simple().to_string(),
+        email,
+        max_uses,
+        used: 0,
+        expires_at: expires_at.timestamp(),
+        created_by: created_by.to_string(),
+        created_at: Utc::now().to_rfc2822(),
+        redeemed_by: vec![],
+    };
+    let document = to_bson(&invite)?.as_document().ok_or("unexpected error when encode invite")?.clone();
+    db.cli.database(&db.name).collection("Invite").insert_one(document, None).await?;
+    Ok(invite)
+}
+
+pub async fn get_invites(db: &Database) -> Result<Vec<Invite>, Box<dyn Error>> {
+    Ok(db.cli
+        .database(&db.name)
+        .collection("Invite")
+        .find(doc! {}, FindOptions::builder().sort(doc! {"expires_at": -1}).build())
+        .await?
+        .filter_map(|document| async move {
+            from_bson::<Invite>(Bson::Document(document.ok()?)).ok()
+        })
+        .collect()
+        .await)
+}
+
+// 用次数、有效期和绑定邮箱的检查放在同一次更新里，并发兑换不会超过 max_uses
+pub async fn redeem_invite(db: &Database, code: &str, email: &str, username: &str) -> Result<(), Box<dyn Error>> {
+    let redeemed = db.cli
+        .database(&db.name)
+        .collection("Invite")
+        .find_one_and_update(doc! {
+            "code": code,
+            "$expr": {"$lt": ["$used", "$max_uses"]},
+            "expires_at": {"$gt": Utc::now().timestamp()},
+            "$or": [{"email": Bson::Null}, {"email": email.to_lowercase()}],
+        }, doc! {"$inc": {"used": 1}, "$push": {"redeemed_by": username}}, None)
+        .await?;
+    match redeemed {
+        Some(_) => Ok(()),
+        None => Err(Box::new(RegisterError::InviteInvalid)),
+    }
+}
+
+// 兑换后注册失败时退回一次使用次数
+pub async fn release_invite(db: &Database, code: &str, username: &str) -> Result<(), Box<dyn Error>> {
+    db.cli
+        .database(&db.name)
+        .collection("Invite")
+        .update_one(
+            doc! {"code": code, "redeemed_by": username},
+            doc! {"$inc": {"used": -1}, "$pull": {"redeemed_by": username}},
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+async fn post_invite_handler(state: web::Data<AppState>, auth: BearerAuth, req: web::Json<NewInvite>) -> impl Responder {
+    let session = json_response!(get_admin_session(&state.db, auth).await).data.unwrap();
+    web::Json(json_response!(timed("post_invite", post_invite(&state.db, &session.username, req.0)).await))
+}
+
+async fn get_invites_handler(state: web::Data<AppState>, auth: BearerAuth) -> impl Responder {
+    json_response!(get_admin_session(&state.db, auth).await);
+    web::Json(json_response!(timed("get_invites", get_invites(&state.db)).await))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/admin/invite")
+            .route(web::post().to(post_invite_handler))
+            .route(web::get().to(get_invites_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use futures_await_test::async_test;
+
+    use crate::resources::invite::{NewInvite, post_invite, redeem_invite, release_invite};
+    use crate::util::testing::TestApp;
+
+    #[async_test]
+    async fn test_redeem_invite() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        let invite = post_invite(db, "admin", NewInvite {
+            email: Some("Teacher@sustech.edu.cn".to_string()),
+            max_uses: Some(1),
+            expire_days: None,
+        }).await.unwrap();
+        assert!(redeem_invite(db, &invite.code, "someone@sustech.edu.cn", "someone").await.is_err());
+        assert!(redeem_invite(db, &invite.code, "teacher@sustech.edu.cn", "teacher").await.is_ok());
+        assert!(redeem_invite(db, &invite.code, "teacher@sustech.edu.cn", "teacher").await.is_err());
+        release_invite(db, &invite.code, "teacher").await.unwrap();
+        assert!(redeem_invite(db, &invite.code, "teacher@sustech.edu.cn", "teacher").await.is_ok());
+    }
+
+    #[async_test]
+    async fn test_post_invite_rejects_bad_input() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        let new_invite = |email: Option<&str>, expire_days: Option<i64>| NewInvite {
+            email: email.map(str::to_string),
+            max_uses: None,
+            expire_days,
+        };
+        for days in &[0, -1, 366, i64::MAX] {
+            assert!(post_invite(db, "admin", new_invite(None, Some(*days))).await.is_err(), "{}", days);
+        }
+        assert!(post_invite(db, "admin", new_invite(Some("not an email"), None)).await.is_err());
+        assert!(post_invite(db, "admin", new_invite(None, Some(365))).await.is_ok());
+        let invite = post_invite(db, "admin", new_invite(Some(" Staff@SUSTech.edu.cn "), Some(1))).await.unwrap();
+        assert_eq!(invite.email, Some("staff@sustech.edu.cn".to_string()));
+    }
+}
@@ -5,3 +5,38 @@ pub mod rate;
 pub mod comment;
 pub mod register_link;
 pub mod detail;
+pub mod teacher;
+pub mod faculty;
+pub mod import;
+pub mod email_queue;
+pub mod invite;
+
+use actix_web::web;
+
+use crate::util::index::IndexSpec;
+
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![
+        user::indexes(), comment::indexes(), course::indexes(), detail::indexes(), rate::indexes(),
+        email_queue::indexes(), register_link::indexes(), invite::indexes(),
+    ]
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+// 所有资源的路由，服务和集成测试共用
+pub fn config(cfg: &mut web::ServiceConfig) {
+    course::config(cfg);
+    rate::config(cfg);
+    session::config(cfg);
+    user::config(cfg);
+    register_link::config(cfg);
+    comment::config(cfg);
+    detail::config(cfg);
+    teacher::config(cfg);
+    faculty::config(cfg);
+    import::config(cfg);
+    email_queue::config(cfg);
+    invite::config(cfg);
+}
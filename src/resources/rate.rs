@@ -1,11 +1,11 @@
 use actix_web::{Responder, web};
-use futures::future;
-use futures::stream::StreamExt;
-use mongodb::bson::{Bson, doc, Document, from_bson};
+use mongodb::bson::{Bson, doc, Document};
 use serde::{Deserialize, Serialize};
 
-use crate::util::database::Database;
-use crate::util::database::DEFAULT_DATABASE;
+use crate::util::index::IndexSpec;
+use crate::util::metrics::timed;
+use crate::util::repository::RateRepository;
+use crate::util::state::AppState;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Rate {
@@ -17,30 +17,17 @@ pub struct Rate {
     easy: f32,
 }
 
-async fn get_rate(db: Option<&Database>, filter: Option<Document>) -> Result<Vec<Rate>, Box<dyn std::error::Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
-    Ok(db
-        .cli
-        .database(&db.name)
-        .collection("Rate")
-        .find(filter, None)
-        .await?
-        .map(|d| {
-            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|d| {
-            from_bson::<Rate>(d.unwrap())
-        })
-        .filter(|x| future::ready(Result::is_ok(x)))
-        .map(|x| x.unwrap())
-        .collect::<Vec<Rate>>()
-        .await)
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![IndexSpec::new("Rate", doc! {"cid": 1})]
 }
 
-async fn get_rate_handler(req: web::Query<Bson>) -> impl Responder {
+async fn get_rate(rates: &dyn RateRepository, filter: Option<Document>) -> Result<Vec<Rate>, Box<dyn std::error::Error>> {
+    rates.find_rates(filter.unwrap_or(doc! {})).await
+}
+
+async fn get_rate_handler(state: web::Data<AppState>, req: web::Query<Bson>) -> impl Responder {
     use crate::json_response;
-    web::Json(json_response!(get_rate(None, req.as_document().cloned()).await))
+    web::Json(json_response!(timed("get_rate", get_rate(state.repos.rates.as_ref(), req.as_document().cloned())).await))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -1,103 +1,257 @@
 use core::fmt;
-use std::collections::HashMap;
 use std::error::Error;
-use std::sync::Mutex;
 
 use actix_web::{Responder, web};
-use chrono::prelude::*;
-use lazy_static::lazy_static;
+use chrono::{Duration, Utc};
+use hex::ToHex;
+use lettre::Address;
+use mongodb::bson::{doc, Document};
+use mongodb::options::ReplaceOptions;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-use crate::util::email_sender::DEFAULT_EMAIL_SENDER;
+use crate::resources::email_queue::enqueue_template;
+use crate::util::config::{RegisterConfig, StaffPolicy};
+use crate::util::database::Database;
+use crate::util::email_sender::EmailSender;
+use crate::util::email_template::{Language, Template};
+use crate::util::index::IndexSpec;
 use crate::json_response;
+use crate::util::metrics::timed;
+use crate::util::state::AppState;
 
 const EXPIRE_TIME: u8 = 30;
 const RETRY_TIME: u8 = 60;
-
-lazy_static! {
-    static ref EMAIL_CODE_DICT: Mutex<HashMap<String, EmailCodeTimeEntry>> = Mutex::new(HashMap::new());
-}
+const MAX_ATTEMPTS: i32 = 5;
+const DEFAULT_DOMAINS: [&str; 4] = ["mail.sustech.edu.cn", "sustech.edu.cn", "mail.sustc.edu.cn", "sustc.edu.cn"];
+const DEFAULT_LOCAL_PART: &str = "[0-9]{8}";
 
 #[derive(Debug)]
 pub enum RegisterError {
-    NotSUSTech,
+    InvalidEmail,
+    DomainNotAllowed,
     NotStudent,
     CodeInvalid,
+    InviteInvalid,
+    TooManyAttempts,
     TooMany,
+    UsernameTaken,
+    EmailTaken,
+    UsernameLength,
+    UsernameCharacter,
+    UsernameReserved,
 }
 
 impl fmt::Display for RegisterError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            RegisterError::NotSUSTech => write!(f, "not SUSTech email"),
-            RegisterError::NotStudent => write!(f, "not student if you want to register please contact us"),
+            RegisterError::InvalidEmail => write!(f, "invalid email address"),
+            RegisterError::DomainNotAllowed => write!(f, "email domain is not allowed"),
+            RegisterError::NotStudent => write!(f, "not student if you want to register please ask us for an invite code"),
             RegisterError::TooMany => write!(f, "too many request for link, please wait 60 seconds"),
             RegisterError::CodeInvalid => write!(f, "invalid verification code"),
+            RegisterError::InviteInvalid => write!(f, "invalid, expired or used up invite code"),
+            RegisterError::TooManyAttempts => write!(f, "too many wrong verification codes, please request a new link"),
+            RegisterError::UsernameTaken => write!(f, "username is already taken"),
+            RegisterError::EmailTaken => write!(f, "email is already registered"),
+            RegisterError::UsernameLength => write!(f, "username must be 3 to 20 characters"),
+            RegisterError::UsernameCharacter => write!(f, "username may only contain letters, digits, _ and -"),
+            RegisterError::UsernameReserved => write!(f, "username is reserved"),
         }
     }
 }
 
 impl Error for RegisterError {}
 
-#[derive(Debug, Clone)]
-pub struct EmailCodeTimeEntry {
-    entry: EmailCodeEntry,
-    last_time: DateTime<Utc>,
+pub struct RegisterPolicy {
+    domains: Vec<String>,
+    local_part: Regex,
+    exceptions: Vec<String>,
+    staff: StaffPolicy,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RegisterLinkQuery {
+    pub email: String,
+    #[serde(default)]
+    pub lang: Language,
+}
+
+// 验证码只通过邮件送达，接口返回时不包含 code
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EmailCodeEntry {
     email: String,
+    #[serde(skip_serializing)]
     pub code: String,
 }
 
-pub fn validate_email(email: &str) -> Result<&str, RegisterError> {
-    if !email[0..8].parse::<u64>().is_ok() {
-        Err(RegisterError::NotStudent)
-    } else if !(email[8..].eq("@mail.sustech.edu.cn") || email[8..].eq("@sustech.edu.cn")
-        || email[8..].eq("@mail.sustc.edu.cn") || email[8..].eq("@sustc.edu.cn")) {
-        Err(RegisterError::NotSUSTech)
-    } else {
-        Ok(email)
-    }
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![
+        IndexSpec::new("EmailCode", doc! {"email": 1}).unique(),
+        IndexSpec::new("EmailCode", doc! {"created_at": 1}).expire_after(EXPIRE_TIME as i64 * 60),
+    ]
 }
 
-pub fn validate_code(email: &str, code: &str) -> Result<(), Box<dyn Error>> {
-    let mut email_dict = EMAIL_CODE_DICT.lock()?;
-    if let Some(entry) = email_dict.get(email) {
-        if Utc::now().signed_duration_since(entry.last_time).num_minutes() < EXPIRE_TIME as i64
-            && code == entry.entry.code {
+fn hash_code(code: &str) -> String {
+    Sha256::digest(code.as_bytes()).to_hex()
+}
+
+impl RegisterPolicy {
+    pub fn new(config: &RegisterConfig) -> Result<RegisterPolicy, Box<dyn Error>> {
+        let lowercase = |list: &Option<Vec<String>>| list
+            .as_ref()
+            .map(|list| list.iter().map(|s| s.to_lowercase()).collect::<Vec<String>>());
+        Ok(RegisterPolicy {
+            domains: lowercase(&config.domains)
+                .unwrap_or_else(|| DEFAULT_DOMAINS.iter().map(|s| s.to_string()).collect()),
+            local_part: Regex::new(&format!("^(?:{})$", config.local_part.as_deref().unwrap_or(DEFAULT_LOCAL_PART)))?,
+            exceptions: lowercase(&config.exceptions).unwrap_or_default(),
+            staff: config.staff.unwrap_or(StaffPolicy::InviteOnly),
+        })
+    }
+
+    pub fn check(&self, email: &str) -> Result<(), RegisterError> {
+        let address = email.parse::<Address>().map_err(|_| RegisterError::InvalidEmail)?;
+        if self.exceptions.contains(&email.to_lowercase()) {
             return Ok(());
         }
-        if Utc::now().signed_duration_since(entry.last_time).num_minutes() >= EXPIRE_TIME as i64 {
-            email_dict.remove(email);
+        if !self.domains.contains(&address.domain().to_lowercase()) {
+            Err(RegisterError::DomainNotAllowed)
+        } else if !self.local_part.is_match(address.user()) && self.staff == StaffPolicy::InviteOnly {
+            Err(RegisterError::NotStudent)
+        } else {
+            Ok(())
         }
     }
-    Err(Box::new(RegisterError::CodeInvalid))
 }
 
-pub async fn get_register_link(email: &str) -> Result<EmailCodeEntry, Box<dyn Error>> {
-    let email = validate_email(email)?;
+// 邮箱不区分大小写，进入注册流程前统一处理，策略检查、验证码和入库用的都是同一个地址
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// 持邀请码注册时不受域名和学号规则限制，只要求地址合法
+pub fn validate_address(email: &str) -> Result<&str, RegisterError> {
+    email.parse::<Address>().map_err(|_| RegisterError::InvalidEmail)?;
+    Ok(email)
+}
 
-    if let Some(entry) = EMAIL_CODE_DICT.lock()?.get(email) {
-        if Utc::now().signed_duration_since(entry.last_time).num_seconds() < RETRY_TIME as i64 {
-            return Err(Box::new(RegisterError::TooMany));
+// 验证成功即删除，验证码只能使用一次；TTL 索引每分钟才清理一次，所以这里也检查过期时间。
+// 返回被删除的记录，后续步骤失败时用 restore_code 放回
+pub async fn consume_code(db: &Database, email: &str, code: &str) -> Result<Document, Box<dyn Error>> {
+    let email_code = db.cli.database(&db.name).collection("EmailCode");
+    let consumed = email_code
+        .find_one_and_delete(doc! {
+            "email": email,
+            "code_hash": hash_code(code),
+            "attempts": {"$lt": MAX_ATTEMPTS},
+            "created_at": {"$gt": Utc::now() - Duration::minutes(EXPIRE_TIME as i64)},
+        }, None)
+        .await?;
+    if let Some(consumed) = consumed {
+        return Ok(consumed);
+    }
+    let entry = email_code
+        .find_one_and_update(doc! {"email": email}, doc! {"$inc": {"attempts": 1}}, None)
+        .await?;
+    match entry {
+        Some(entry) if entry.get_i32("attempts").unwrap_or(0) + 1 >= MAX_ATTEMPTS => {
+            email_code.delete_one(doc! {"email": email}, None).await?;
+            Err(Box::new(RegisterError::TooManyAttempts))
         }
+        _ => Err(Box::new(RegisterError::CodeInvalid)),
+    }
+}
+
+// 期间已经申请了新验证码时唯一索引会拒绝插入，以新的为准
+pub async fn restore_code(db: &Database, entry: Document) -> Result<(), Box<dyn Error>> {
+    db.cli.database(&db.name).collection("EmailCode").insert_one(entry, None).await?;
+    Ok(())
+}
+
+pub async fn get_register_link(db: &Database, sender: &EmailSender, policy: &RegisterPolicy, email: &str, lang: Language) -> Result<EmailCodeEntry, Box<dyn Error>> {
+    let email = &normalize_email(email);
+    policy.check(email)?;
+    let email_code = db.cli.database(&db.name).collection("EmailCode");
+
+    let recent = email_code
+        .count_documents(doc! {"email": email, "created_at": {"$gt": Utc::now() - Duration::seconds(RETRY_TIME as i64)}}, None)
+        .await?;
+    if recent > 0 {
+        return Err(Box::new(RegisterError::TooMany));
     }
     let entry = EmailCodeEntry {
         email: email.to_string(),
         code: Uuid::new_v4().to_string(),
     };
-    EMAIL_CODE_DICT.lock()?.insert(email.to_string(), EmailCodeTimeEntry {
-        entry: entry.clone(),
-        last_time: Utc::now(),
-    });
-    DEFAULT_EMAIL_SENDER.send(email, "Flow 注册链接", &format!("https://sustechflow.top/signup?vcode={}", entry.code)).await?;
+    // 重新申请会覆盖旧的验证码并重置尝试次数
+    email_code
+        .replace_one(doc! {"email": email}, doc! {
+            "email": email,
+            "code_hash": hash_code(&entry.code),
+            "attempts": 0,
+            "created_at": Utc::now(),
+        }, ReplaceOptions::builder().upsert(true).build())
+        .await?;
+    enqueue_template(db, sender, email, Template::Registration, lang, &[("code", &entry.code)]).await?;
     Ok(entry)
 }
 
-pub async fn get_register_link_handler(req: web::Query<&str>) -> impl Responder {
-    web::Json(json_response!(get_register_link(req.0).await))
+pub async fn get_register_link_handler(state: web::Data<AppState>, req: web::Query<RegisterLinkQuery>) -> impl Responder {
+    web::Json(json_response!(timed("get_register_link", get_register_link(&state.db, &state.email_sender, &state.policies.register, &req.email, req.lang)).await))
 }
 
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/register_link")
+            .route(web::get().to(get_register_link_handler))
+    );
+}
+
+
+#[cfg(test)]
+mod test {
+    use crate::resources::register_link::{normalize_email, RegisterPolicy};
+    use crate::util::config::{RegisterConfig, StaffPolicy};
+
+    fn policy(staff: StaffPolicy) -> RegisterPolicy {
+        RegisterPolicy::new(&RegisterConfig {
+            domains: Some(vec!["mail.sustech.edu.cn".to_string(), "sustech.edu.cn".to_string()]),
+            local_part: Some("[0-9]{8}".to_string()),
+            exceptions: Some(vec!["Visitor@example.com".to_string()]),
+            staff: Some(staff),
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_register_policy() {
+        let policy = policy(StaffPolicy::InviteOnly);
+        assert!(policy.check("11712009@mail.sustech.edu.cn").is_ok());
+        assert!(policy.check("11712009@MAIL.SUSTECH.EDU.CN").is_ok());
+        assert!(policy.check("visitor@example.com").is_ok());
+        assert!(policy.check("zhangsan@sustech.edu.cn").is_err());
+        assert!(policy.check("11712009@example.com").is_err());
+        assert!(policy.check("117120091@mail.sustech.edu.cn").is_err());
+    }
+
+    #[test]
+    fn test_normalize_email() {
+        assert_eq!(normalize_email(" 11712009@MAIL.SUSTech.edu.cn "), "11712009@mail.sustech.edu.cn");
+        assert_eq!(normalize_email("visitor@example.com"), "visitor@example.com");
+    }
+
+    #[test]
+    fn test_register_policy_staff_allowed() {
+        assert!(policy(StaffPolicy::Allow).check("zhangsan@sustech.edu.cn").is_ok());
+    }
+
+    #[test]
+    fn test_register_policy_malformed() {
+        let policy = policy(StaffPolicy::Allow);
+        for email in &["", "1171", "学生@sustech.edu.cn@", "no-at-sign", "@sustech.edu.cn"] {
+            assert!(policy.check(email).is_err(), "{}", email);
+        }
+    }
+}
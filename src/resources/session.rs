@@ -1,5 +1,6 @@
 use std::clone::Clone;
 use std::collections::HashMap;
+use std::env;
 use std::error::Error;
 use std::fmt;
 use std::sync::Mutex;
@@ -11,14 +12,18 @@ use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use timer::Timer;
 use uuid::Uuid;
-use mongodb::bson::{doc, Bson, Document, from_bson};
+use futures::stream::StreamExt;
+use hex::ToHex;
+use mongodb::bson::{doc, Bson, Document, from_bson, to_bson};
+use sha2::{Digest, Sha256};
 
-use crate::error;
 use crate::json_response;
 use crate::util::crypto::verify_helper;
 use crate::util::database::Database;
-use crate::util::database::DEFAULT_DATABASE;
+use crate::util::metrics::inc_rate_limited;
 use crate::resources::user::User;
+use crate::util::repository::UserRepository;
+use crate::util::state::AppState;
 
 const EXPIRE_TIME: u8 = 1;
 const API_LIMIT: u64 = 1000;
@@ -32,6 +37,17 @@ pub struct Session {
     pub api_count: u64
 }
 
+// 持久化到 Session 集合的登录态，只保存 token 的哈希；instance 区分多个实例各自的登录态
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredSession {
+    instance: String,
+    token_hash: String,
+    username: String,
+    email: String,
+    login_time: String,
+    api_count: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthInfo {
     pub username: String,
@@ -44,6 +60,7 @@ pub enum AuthError {
     NotLogin,
     TooFrequent,
     Expired,
+    NotAdmin,
 }
 
 impl fmt::Display for AuthError {
@@ -52,7 +69,8 @@ impl fmt::Display for AuthError {
             AuthError::WrongPassword => write!(f, "wrong password"),
             AuthError::NotLogin => write!(f, "not login"),
             AuthError::Expired => write!(f, "expired"),
-            AuthError::TooFrequent => write!(f, "too frequent")
+            AuthError::TooFrequent => write!(f, "too frequent"),
+            AuthError::NotAdmin => write!(f, "permission denied"),
         }
     }
 }
@@ -60,26 +78,46 @@ impl fmt::Display for AuthError {
 impl Error for AuthError {}
 
 lazy_static! {
+    // 以 token 的 SHA-256 为键，池中的 Session 不保存 token 明文
     pub static ref SESSION_POOL: Mutex < HashMap < String, Session > > = Mutex::new(HashMap::new());
     pub static ref API_CLEANER: Mutex<Timer> = Mutex::new(Timer::with_capacity(1));
     pub static ref API_COUNTER: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
 }
 
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).to_hex()
+}
+
+// 多实例部署时用 FLOW_INSTANCE 或主机名区分，重启后只取回本实例的登录态
+fn instance_id() -> String {
+    env::var("FLOW_INSTANCE")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "default".to_string())
+}
+
+fn is_alive(session: &Session) -> bool {
+    DateTime::parse_from_rfc2822(&session.login_time)
+        .map(|login_time| Utc::now().signed_duration_since(login_time).num_days() < EXPIRE_TIME as i64)
+        .unwrap_or(false)
+}
+
 pub async fn get_session(auth: BearerAuth) -> Result<Session, Box<dyn Error>> {
     let mut session_pool = SESSION_POOL.lock()?;
-    match session_pool.get(auth.token()) {
+    let key = hash_token(auth.token());
+    match session_pool.get(&key) {
         Some(session) => {
             if Utc::now().signed_duration_since(DateTime::parse_from_rfc2822(&session.login_time)?).num_days() < EXPIRE_TIME as i64 {
                 let mut api_counter = API_COUNTER.lock()?;
                 let count = api_counter.get_mut(&session.email).ok_or("unexpected error when try to get api counter")?;
                 if *count > API_LIMIT {
+                    inc_rate_limited();
                     Err(Box::new(AuthError::TooFrequent))
                 } else {
                     *count += 1;
-                    Ok::<Session, Box<dyn Error>>(session.clone())
+                    Ok::<Session, Box<dyn Error>>(Session { token: auth.token().to_string(), ..session.clone() })
                 }
             } else {
-                session_pool.remove(auth.token());
+                session_pool.remove(&key);
                 Err(Box::new(AuthError::Expired))
             }
         }
@@ -87,34 +125,33 @@ pub async fn get_session(auth: BearerAuth) -> Result<Session, Box<dyn Error>> {
     }
 }
 
-async fn user_info(db: Option<&Database>, username_or_email: &str) -> Result<User, Box<dyn std::error::Error>> {
-    let db = db.unwrap_or(&DEFAULT_DATABASE);
-    let user_doc = db
-        .cli
-        .database(&db.name)
-        .collection("User")
-        .find_one(
-            doc! {"$or" :
-                [
-                    {"username": &username_or_email},
-                    {"email": &username_or_email},
-                ]
-            }, None,
-        )
-        .await?;
-    let user_doc = Bson::Document(user_doc.ok_or("user not found")?);
-    error!(from_bson::<User>(user_doc))
-}
-
-pub async fn post_session(auth: AuthInfo) -> Result<Session, Box<dyn Error>> {
-    let mut session_pool = SESSION_POOL.lock()?;
-    let mut api_counter = API_COUNTER.lock()?;
-    let user = user_info(None, &auth.username).await?;
+pub async fn get_admin_session(db: &Database, auth: BearerAuth) -> Result<Session, Box<dyn Error>> {
+    let session = get_session(auth).await?;
+    if user_info(db, &session.username).await?.admin {
+        Ok(session)
+    } else {
+        Err(Box::new(AuthError::NotAdmin))
+    }
+}
+
+async fn user_info(users: &dyn UserRepository, username_or_email: &str) -> Result<User, Box<dyn std::error::Error>> {
+    Ok(users
+        .find_user(doc! {"$or" :
+            [
+                {"username": &username_or_email},
+                // 新注册的邮箱都是小写，早先注册的可能不是
+                {"email": {"$in": [username_or_email, username_or_email.to_lowercase()]}},
+            ]
+        })
+        .await?
+        .ok_or("user not found")?)
+}
+
+// 查询用户要 await，std 的 Mutex 只能在查询结束后再加锁
+pub async fn post_session(users: &dyn UserRepository, auth: AuthInfo) -> Result<Session, Box<dyn Error>> {
+    let user = user_info(users, &auth.username).await?;
     if verify_helper(&user.permanent_token, &auth.password) {
-        if !api_counter.contains_key(&user.email) {
-            api_counter.insert(user.email.clone(), 0);
-        }
-        let &count = api_counter.get(&user.email).unwrap();
+        let count = *API_COUNTER.lock()?.entry(user.email.clone()).or_insert(0);
         let uuid = Uuid::new_v4().to_string();
         let session = Session {
             email: user.email,
@@ -123,7 +160,7 @@ pub async fn post_session(auth: AuthInfo) -> Result<Session, Box<dyn Error>> {
             login_time: Utc::now().to_rfc2822(),
             api_count: count
         };
-        session_pool.insert(uuid, session.clone());
+        SESSION_POOL.lock()?.insert(hash_token(&uuid), Session { token: String::new(), ..session.clone() });
 
         Ok(session)
     } else {
@@ -131,10 +168,93 @@ pub async fn post_session(auth: AuthInfo) -> Result<Session, Box<dyn Error>> {
     }
 }
 
+// 只读查询，不计入 API 次数，供日志等场景使用
+pub fn session_username(token: &str) -> Option<String> {
+    SESSION_POOL.lock().ok()?.get(&hash_token(token)).map(|session| session.username.clone())
+}
+
+pub fn revoke_sessions(username: &str) -> Result<Vec<Session>, Box<dyn Error>> {
+    let mut session_pool = SESSION_POOL.lock()?;
+    let tokens = session_pool
+        .iter()
+        .filter(|(_, session)| session.username == username)
+        .map(|(token, _)| token.clone())
+        .collect::<Vec<String>>();
+    Ok(tokens
+        .iter()
+        .filter_map(|token| session_pool.remove(token))
+        .collect())
+}
+
+// 停机时把本实例的登录态写入 Session 集合，启动时读回并删除，重启不会让用户掉线
+pub async fn persist_sessions(db: &Database) -> Result<usize, Box<dyn Error>> {
+    let instance = instance_id();
+    let documents = SESSION_POOL
+        .lock()?
+        .iter()
+        .map(|(token_hash, session)| StoredSession {
+            instance: instance.clone(),
+            token_hash: token_hash.clone(),
+            username: session.username.clone(),
+            email: session.email.clone(),
+            login_time: session.login_time.clone(),
+            api_count: session.api_count,
+        })
+        .filter_map(|session| to_bson(&session).ok()?.as_document().cloned())
+        .collect::<Vec<Document>>();
+    let collection = db.cli.database(&db.name).collection("Session");
+    collection.delete_many(doc! {"instance": &instance}, None).await?;
+    if documents.is_empty() {
+        return Ok(0);
+    }
+    let persisted = documents.len();
+    collection.insert_many(documents, None).await?;
+    Ok(persisted)
+}
+
+pub async fn restore_sessions(db: &Database) -> Result<usize, Box<dyn Error>> {
+    let instance = instance_id();
+    let collection = db.cli.database(&db.name).collection("Session");
+    // 旧版本按明文 token 保存的记录直接删除
+    collection.delete_many(doc! {"token_hash": {"$exists": false}}, None).await?;
+    let mut cursor = collection.find(doc! {"instance": &instance}, None).await?;
+    let mut sessions = vec![];
+    while let Some(document) = cursor.next().await {
+        let stored = match document.map_err(|e| e.to_string()).and_then(|d| from_bson::<StoredSession>(Bson::Document(d)).map_err(|e| e.to_string())) {
+            Ok(stored) => stored,
+            Err(err) => {
+                log::warn!("skip unreadable session: {}", err);
+                continue;
+            }
+        };
+        let session = Session {
+            username: stored.username,
+            email: stored.email,
+            token: String::new(),
+            login_time: stored.login_time,
+            api_count: stored.api_count,
+        };
+        if is_alive(&session) {
+            sessions.push((stored.token_hash, session));
+        }
+    }
+    let restored = sessions.len();
+    {
+        let mut session_pool = SESSION_POOL.lock()?;
+        let mut api_counter = API_COUNTER.lock()?;
+        for (token_hash, session) in sessions {
+            api_counter.entry(session.email.clone()).or_insert(session.api_count);
+            session_pool.insert(token_hash, session);
+        }
+    }
+    collection.delete_many(doc! {"instance": &instance}, None).await?;
+    Ok(restored)
+}
+
 async fn delete_session(req: BearerAuth) -> Result<Session, Box<dyn Error>> {
     let mut session_pool = SESSION_POOL.lock()?;
-    match session_pool.remove(req.token()) {
-        Some(session) => Ok::<Session, Box<dyn Error>>(session.clone()),
+    match session_pool.remove(&hash_token(req.token())) {
+        Some(session) => Ok::<Session, Box<dyn Error>>(Session { token: req.token().to_string(), ..session }),
         None => Err::<Session, Box<dyn Error>>(Box::new(AuthError::NotLogin)),
     }
 }
@@ -143,8 +263,8 @@ async fn delete_session_handler(req: BearerAuth) -> impl Responder {
     web::Json(json_response!(delete_session(req).await))
 }
 
-async fn post_session_handler(auth: web::Json<AuthInfo>) -> impl Responder {
-    web::Json(json_response!(post_session(auth.0).await))
+async fn post_session_handler(state: web::Data<AppState>, auth: web::Json<AuthInfo>) -> impl Responder {
+    web::Json(json_response!(post_session(state.repos.users.as_ref(), auth.0).await))
 }
 
 async fn get_session_handler(auth: BearerAuth) -> impl Responder {
@@ -163,4 +283,61 @@ pub fn config(cfg: &mut web::ServiceConfig) {
             .route(web::get().to(get_session_handler))
             .route(web::delete().to(delete_session_handler))
     );
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use mongodb::bson::doc;
+
+    use crate::resources::session::{AuthInfo, hash_token, instance_id, persist_sessions, post_session, restore_sessions, session_username, SESSION_POOL};
+    use crate::util::memory_store::MemoryStore;
+    use crate::util::testing::TestApp;
+
+    fn users(username: &str) -> MemoryStore {
+        let store = MemoryStore::new();
+        store.insert("User", doc! {
+            "username": username,
+            "email": format!("{}@mail.sustech.edu.cn", username),
+            "permanent_token": bcrypt::hash("secret", 4).unwrap(),
+            "learnt_course": [],
+        }).unwrap();
+        store
+    }
+
+    fn auth(username: &str) -> AuthInfo {
+        AuthInfo { username: username.to_string(), password: "secret".to_string() }
+    }
+
+    #[actix_rt::test]
+    async fn test_pool_keyed_by_token_hash() {
+        let session = post_session(&users("hash_user"), auth("hash_user")).await.unwrap();
+        let pool = SESSION_POOL.lock().unwrap();
+        assert!(!pool.contains_key(&session.token));
+        assert_eq!(pool.get(&hash_token(&session.token)).unwrap().token, "");
+        drop(pool);
+        assert_eq!(session_username(&session.token), Some("hash_user".to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn test_persist_and_restore_sessions() {
+        let app = TestApp::new().await.unwrap();
+        let db = app.db();
+        let session = post_session(&users("persist_user"), auth("persist_user")).await.unwrap();
+        persist_sessions(db).await.unwrap();
+        let stored = db.cli.database(&db.name).collection("Session");
+        assert!(stored.find_one(doc! {"token": &session.token}, None).await.unwrap().is_none());
+        assert!(stored.find_one(doc! {"token_hash": hash_token(&session.token)}, None).await.unwrap().is_some());
+
+        // 其他实例的记录保留，坏记录和旧版明文记录跳过
+        stored.insert_one(doc! {"instance": "other", "token_hash": "x", "username": "u", "email": "e", "login_time": "", "api_count": 0}, None).await.unwrap();
+        stored.insert_one(doc! {"instance": instance_id(), "token_hash": 1}, None).await.unwrap();
+        stored.insert_one(doc! {"token": "plain", "username": "u"}, None).await.unwrap();
+        SESSION_POOL.lock().unwrap().remove(&hash_token(&session.token));
+        assert_eq!(session_username(&session.token), None);
+
+        assert!(restore_sessions(db).await.unwrap() >= 1);
+        assert_eq!(session_username(&session.token), Some("persist_user".to_string()));
+        assert_eq!(stored.count_documents(doc! {"instance": "other"}, None).await.unwrap(), 1);
+        assert_eq!(stored.count_documents(doc! {"instance": instance_id()}, None).await.unwrap(), 0);
+        assert_eq!(stored.count_documents(doc! {"token": "plain"}, None).await.unwrap(), 0);
+    }
+}
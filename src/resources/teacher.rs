@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use actix_web::{Responder, web};
+use futures::future;
+use futures::stream::StreamExt;
+use mongodb::bson::{Bson, doc, from_bson};
+use serde::{Deserialize, Serialize};
+
+use crate::json_response;
+use crate::util::database::Database;
+use crate::util::metrics::timed;
+use crate::util::state::AppState;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TaughtCourse {
+    cid: String,
+    name: String,
+    faculty: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Teacher {
+    name: String,
+    courses: Vec<TaughtCourse>,
+    likes: f32,
+    useful: f32,
+    easy: f32,
+    comments: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct TeacherSection {
+    teacher: String,
+    cid: String,
+    name: String,
+    faculty: String,
+}
+
+// 按评论里写的老师名字汇总的评分，名字还没有统一写法
+#[derive(Debug, Deserialize)]
+struct TaughtRate {
+    taught: String,
+    likes: f32,
+    useful: f32,
+    easy: f32,
+    comments: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TeacherQuery {
+    name: Option<String>,
+}
+
+/// 统一教师姓名的写法：全角字符转半角，中文姓名去掉所有空白，
+/// 英文姓名合并连续空白，并去掉末尾的“老师”。
+pub fn normalize_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| match c {
+            '\u{3000}' => ' ',
+            '\u{ff01}'..='\u{ff5e}' => std::char::from_u32(c as u32 - 0xfee0).unwrap_or(c),
+            _ => c,
+        })
+        .collect::<String>();
+    let name = name.trim().trim_end_matches("老师").trim();
+    if name.is_ascii() {
+        name.split_whitespace().collect::<Vec<&str>>().join(" ")
+    } else {
+        name.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+}
+
+fn teacher_key(name: &str) -> String {
+    normalize_name(name).to_lowercase()
+}
+
+pub async fn get_teacher(db: &Database, name: Option<&str>) -> Result<Vec<Teacher>, Box<dyn Error>> {
+    let database = db.cli.database(&db.name);
+    let unwind = doc! { "$unwind": "$taught_by" };
+    let aggregator = doc! {
+                "$group" : {
+                    "_id" : {"teacher": "$taught_by", "cid": "$cid"},
+                    "teacher" : {"$first": "$taught_by"},
+                    "cid" : {"$first": "$cid"},
+                    "name" : {"$first": "$name"},
+                    "faculty" : {"$first": "$faculty"},
+                }
+            };
+    let sections = database
+        .collection("Course")
+        .aggregate(vec![unwind, aggregator], None)
+        .await?
+        .map(|d| {
+            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|d| {
+            from_bson::<TeacherSection>(d.unwrap())
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|x| x.unwrap())
+        .collect::<Vec<TeacherSection>>()
+        .await;
+
+    let wanted = name.map(teacher_key);
+    let mut teachers = BTreeMap::<String, Teacher>::new();
+    for section in sections {
+        let key = teacher_key(&section.teacher);
+        if key.is_empty() || wanted.as_ref().map_or(false, |w| w != &key) {
+            continue;
+        }
+        let teacher = teachers.entry(key).or_insert_with(|| Teacher {
+            name: normalize_name(&section.teacher),
+            courses: vec![],
+            likes: 0.0,
+            useful: 0.0,
+            easy: 0.0,
+            comments: 0,
+        });
+        if !teacher.courses.iter().any(|c| c.cid == section.cid) {
+            teacher.courses.push(TaughtCourse {
+                cid: section.cid,
+                name: section.name,
+                faculty: section.faculty,
+            });
+        }
+    }
+
+    // 同一条评论重复写同一个名字只算一次；不同写法在下面合并时才能识别，会各算一次
+    let pipeline = vec![
+        doc! { "$unwind": "$taught" },
+        doc! { "$group": {"_id": {"comment": "$_id", "taught": "$taught"}, "rate": {"$first": "$rate"}} },
+        doc! {
+            "$group": {
+                "_id": "$_id.taught",
+                "taught": {"$first": "$_id.taught"},
+                "likes": {"$sum": "$rate.likes"},
+                "useful": {"$sum": "$rate.useful"},
+                "easy": {"$sum": "$rate.easy"},
+                "comments": {"$sum": 1},
+            }
+        },
+    ];
+    let rates = database
+        .collection("Comment")
+        .aggregate(pipeline, None)
+        .await?
+        .map(|d| {
+            Ok::<Bson, mongodb::error::Error>(Bson::Document(d?))
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|d| {
+            from_bson::<TaughtRate>(d.unwrap())
+        })
+        .filter(|x| future::ready(Result::is_ok(x)))
+        .map(|x| x.unwrap())
+        .collect::<Vec<TaughtRate>>()
+        .await;
+
+    for rate in rates {
+        if let Some(teacher) = teachers.get_mut(&teacher_key(&rate.taught)) {
+            teacher.likes += rate.likes;
+            teacher.useful += rate.useful;
+            teacher.easy += rate.easy;
+            teacher.comments += rate.comments as usize;
+        }
+    }
+
+    Ok(teachers
+        .into_iter()
+        .map(|(_, mut teacher)| {
+            if teacher.comments > 0 {
+                let count = teacher.comments as f32;
+                teacher.likes /= count;
+                teacher.useful /= count;
+                teacher.easy /= count;
+            }
+            teacher
+        })
+        .collect())
+}
+
+async fn get_teacher_handler(state: web::Data<AppState>, req: web::Query<TeacherQuery>) -> impl Responder {
+    web::Json(json_response!(timed("get_teacher", get_teacher(&state.db, req.name.as_deref())).await))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/teacher")
+            .route(web::get().to(get_teacher_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use futures_await_test::async_test;
+
+    use crate::resources::teacher::{get_teacher, normalize_name};
+    use crate::util::testing::TestApp;
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name(" 张 三 "), "张三");
+        assert_eq!(normalize_name("张三\u{3000}老师"), "张三");
+        assert_eq!(normalize_name("John   Smith "), "John Smith");
+        assert_eq!(normalize_name("\u{ff2a}ohn Smith"), "John Smith");
+    }
+
+    #[async_test]
+    async fn test_get_teacher() {
+        let app = TestApp::new().await.unwrap();
+        app.seed_catalog().await.unwrap();
+        let teachers = get_teacher(app.db(), None).await.unwrap();
+        assert_eq!(teachers.iter().map(|t| t.name.as_str()).collect::<Vec<&str>>(), vec!["Alice", "Bob", "Carol"]);
+
+        // "Alice " 和 "Alice" 是同一个人：两门课，三条评论
+        let alice = get_teacher(app.db(), Some("alice")).await.unwrap();
+        assert_eq!(alice.len(), 1);
+        let mut cids = alice[0].courses.iter().map(|c| c.cid.as_str()).collect::<Vec<&str>>();
+        cids.sort();
+        assert_eq!(cids, vec!["CS101", "CS102"]);
+        assert_eq!(alice[0].comments, 3);
+        assert_eq!(alice[0].likes, 4.0);
+        assert_eq!(alice[0].easy, 2.0);
+
+        let bob = get_teacher(app.db(), Some("Bob")).await.unwrap();
+        assert_eq!(bob[0].comments, 1);
+        assert_eq!(bob[0].useful, 2.0);
+        // 没有评论的老师评分为 0
+        let carol = get_teacher(app.db(), Some("Carol")).await.unwrap();
+        assert_eq!((carol[0].comments, carol[0].likes), (0, 0.0));
+    }
+}
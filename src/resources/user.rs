@@ -1,19 +1,26 @@
 use std::error::Error;
+use std::fmt;
 
 use actix_web::{Responder, web};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use bcrypt::hash;
-use mongodb::bson::{Bson, doc, from_bson};
+use mongodb::bson::{Bson, doc};
 use mongodb::bson::Document;
 use serde::{Deserialize, Serialize};
 
 use crate::{error, util::database::Database};
 use crate::json_response;
-use crate::resources::register_link::{validate_code, validate_email};
-use crate::resources::session::{AuthInfo, get_session, post_session, Session};
-use crate::util::crypto::BCRYPT_COST;
-use crate::util::database::DEFAULT_DATABASE;
+use crate::resources::comment::{anonymize_comment_by, Comment, delete_comment_by, get_comment_by};
+use crate::resources::invite::{redeem_invite, release_invite};
+use crate::resources::register_link::{consume_code, normalize_email, RegisterError, RegisterPolicy, restore_code, validate_address};
+use crate::resources::session::{AuthError, AuthInfo, get_session, post_session, revoke_sessions, Session, SESSION_POOL};
+use crate::util::config::{AccountConfig, CommentPolicy};
+use crate::util::crypto::{BCRYPT_COST, verify_helper};
+use crate::util::index::IndexSpec;
+use crate::util::metrics::timed;
 use crate::util::ops::PatchOperator;
+use crate::util::repository::{Repositories, UserRepository};
+use crate::util::state::AppState;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct User {
@@ -21,6 +28,8 @@ pub struct User {
     pub(crate) email: String,
     pub(crate) permanent_token: String,
     pub(crate) learnt_course: Vec<String>,
+    #[serde(default)]
+    pub(crate) admin: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -28,68 +37,272 @@ pub struct RegisterInfo {
     pub username: String,
     pub password: String,
     pub email: String,
+    #[serde(default)]
     pub vcode: String,
+    // 有邀请码时代替邮箱验证码
+    #[serde(default)]
+    pub invite: Option<String>,
 }
 
-pub async fn get_user(db: Option<&Database>, username: &str) -> Result<User, Box<dyn Error>> {
-    let db = db.unwrap_or(&DEFAULT_DATABASE);
-    let user_doc = db
-        .cli.database(&db.name)
-        .collection("User")
-        .find_one(doc! {"username": username}, None)
-        .await?
-        .ok_or("user not found")?;
-    Ok(from_bson::<User>(Bson::Document(user_doc))?)
+#[derive(Debug)]
+pub enum UserPatchError {
+    NotPatchable(String),
+    InvalidValue(String),
+}
+
+impl fmt::Display for UserPatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserPatchError::NotPatchable(field) => write!(f, "{} cannot be patched", field),
+            UserPatchError::InvalidValue(field) => write!(f, "{} can only be set to a valid value", field),
+        }
+    }
+}
+
+impl Error for UserPatchError {}
+
+// 导出用的用户信息，不含密码哈希
+#[derive(Debug, Serialize)]
+pub struct UserProfile {
+    username: String,
+    email: String,
+    learnt_course: Vec<String>,
+    admin: bool,
+}
+
+impl From<User> for UserProfile {
+    fn from(user: User) -> UserProfile {
+        UserProfile {
+            username: user.username,
+            email: user.email,
+            learnt_course: user.learnt_course,
+            admin: user.admin,
+        }
+    }
 }
 
-pub async fn post_user(db: Option<&Database>, register_info: RegisterInfo) -> Result<Session, Box<dyn Error>> {
+#[derive(Debug, Serialize)]
+pub struct SessionRecord {
+    login_time: String,
+    api_count: u64,
+}
+
+// helpful / not_helpful 只作为计数存在评论上，没有按用户记录投票，因此导出中没有投票
+#[derive(Debug, Serialize)]
+pub struct UserArchive {
+    profile: UserProfile,
+    comments: Vec<Comment>,
+    sessions: Vec<SessionRecord>,
+    exported_at: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteConfirm {
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Default)]
+pub struct DeleteReport {
+    sessions_revoked: usize,
+    comments_deleted: i64,
+    comments_anonymized: i64,
+}
+
+const USERNAME_MIN: usize = 3;
+const USERNAME_MAX: usize = 20;
+const RESERVED_USERNAMES: [&str; 12] = [
+    "admin", "administrator", "root", "system", "flow", "sustechflow",
+    "anonymous", "deleted", "null", "undefined", "support", "help",
+];
+
+#[derive(Debug, Deserialize)]
+pub struct AvailableQuery {
+    pub username: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Availability {
+    available: bool,
+    reason: Option<String>,
+}
+
+pub fn indexes() -> Vec<IndexSpec> {
+    vec![
+        IndexSpec::new("User", doc! {"username": 1}).unique(),
+        IndexSpec::new("User", doc! {"email": 1}).unique(),
+    ]
+}
+
+pub async fn get_user(users: &dyn UserRepository, username: &str) -> Result<User, Box<dyn Error>> {
+    Ok(users.find_user(doc! {"username": username}).await?.ok_or("user not found")?)
+}
+
+pub fn validate_username(username: &str) -> Result<&str, RegisterError> {
+    let length = username.chars().count();
+    if length < USERNAME_MIN || length > USERNAME_MAX {
+        Err(RegisterError::UsernameLength)
+    } else if !username.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+        Err(RegisterError::UsernameCharacter)
+    } else if RESERVED_USERNAMES.iter().any(|r| r.eq_ignore_ascii_case(username)) {
+        Err(RegisterError::UsernameReserved)
+    } else {
+        Ok(username)
+    }
+}
+
+async fn check_taken(users: &dyn UserRepository, username: &str, email: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if users.count_users(doc! {"username": username}).await? > 0 {
+        return Err(Box::new(RegisterError::UsernameTaken));
+    }
+    if let Some(email) = email {
+        if users.count_users(doc! {"email": email}).await? > 0 {
+            return Err(Box::new(RegisterError::EmailTaken));
+        }
+    }
+    Ok(())
+}
+
+pub async fn get_available(users: &dyn UserRepository, username: &str) -> Result<Availability, Box<dyn Error>> {
+    let reason = match validate_username(username) {
+        Ok(_) => check_taken(users, username, None).await.err().map(|e| e.to_string()),
+        Err(err) => Some(err.to_string()),
+    };
+    Ok(Availability { available: reason.is_none(), reason })
+}
+
+pub async fn post_user(db: &Database, policy: &RegisterPolicy, register_info: RegisterInfo) -> Result<Session, Box<dyn Error>> {
     let username = register_info.username;
     let password = register_info.password;
     let code = register_info.vcode;
-    let email = register_info.email;
-    let db = db.unwrap_or(&DEFAULT_DATABASE);
-    validate_code(&email, &code)?;
-    validate_email(&email)?;
+    let email = normalize_email(&register_info.email);
+    validate_username(&username)?;
     let hash = hash(&password, BCRYPT_COST)?;
-    db.cli.database(&db.name)
+    let consumed = match &register_info.invite {
+        Some(invite) => {
+            validate_address(&email)?;
+            check_taken(db, &username, Some(&email)).await?;
+            redeem_invite(db, invite, &email, &username).await?;
+            None
+        }
+        None => {
+            policy.check(&email)?;
+            check_taken(db, &username, Some(&email)).await?;
+            Some(consume_code(db, &email, &code).await?)
+        }
+    };
+    // 两个请求同时通过检查时由唯一索引兜底
+    let inserted = db.cli.database(&db.name)
         .collection("User")
         .insert_one(doc! {
             "username": username.to_string(),
             "permanent_token": hash,
-            "email": email
-        }, None).await?;
-    Ok(post_session(AuthInfo {
+            "email": &email,
+            "learnt_course": []
+        }, None).await;
+    if let Err(err) = inserted {
+        // 没注册成功就退回邀请码次数或验证码，用户可以重试
+        let rollback = match (&register_info.invite, consumed) {
+            (Some(invite), _) => release_invite(db, invite, &username).await,
+            (None, Some(entry)) => restore_code(db, entry).await,
+            (None, None) => Ok(()),
+        };
+        if let Err(rollback_err) = rollback {
+            log::warn!("rollback registration of {} failed: {}", username, rollback_err);
+        }
+        return Err(match err.to_string() {
+            msg if msg.contains("E11000") && msg.contains("email") => Box::new(RegisterError::EmailTaken),
+            msg if msg.contains("E11000") => Box::new(RegisterError::UsernameTaken),
+            _ => Box::new(err),
+        });
+    }
+    Ok(post_session(db, AuthInfo {
         username: username.to_string(),
         password: password.to_string(),
     }).await?)
 }
 
-pub async fn patch_user(db: Option<&Database>, filter: Document, op: PatchOperator) -> Result<Bson, Box<dyn Error>> {
-    let db = db.unwrap_or(&*DEFAULT_DATABASE);
-    Ok(db
-        .cli
-        .database(&db.name)
-        .collection("User")
-        .update_one(filter, op.as_op(), None)
-        .await?
-        .upserted_id
-        .ok_or("comment patch failed")?
-    )
+// 用户只能修改 learnt_course；admin、permanent_token、email 等不能通过这个接口修改
+fn check_patch(op: &PatchOperator) -> Result<(), UserPatchError> {
+    let field = match op {
+        PatchOperator::AddToSet(field, _) | PatchOperator::Set(field, _)
+        | PatchOperator::Inc(field, _) | PatchOperator::RmFromSet(field, _) => field,
+    };
+    if field != "learnt_course" {
+        return Err(UserPatchError::NotPatchable(field.clone()));
+    }
+    match op {
+        PatchOperator::AddToSet(_, Bson::String(_)) | PatchOperator::RmFromSet(_, Bson::String(_)) => Ok(()),
+        PatchOperator::Set(_, Bson::Array(courses)) if courses.iter().all(|c| c.as_str().is_some()) => Ok(()),
+        _ => Err(UserPatchError::InvalidValue(field.clone())),
+    }
+}
+
+pub async fn patch_user(users: &dyn UserRepository, filter: Document, op: PatchOperator) -> Result<i64, Box<dyn Error>> {
+    check_patch(&op)?;
+    users.update_user(filter, op.as_op()).await
+}
+
+pub async fn export_user(repos: &Repositories, username: &str) -> Result<UserArchive, Box<dyn Error>> {
+    let profile = UserProfile::from(get_user(repos.users.as_ref(), username).await?);
+    let sessions = SESSION_POOL
+        .lock()?
+        .values()
+        .filter(|session| session.username == username)
+        .map(|session| SessionRecord {
+            login_time: session.login_time.clone(),
+            api_count: session.api_count,
+        })
+        .collect();
+    Ok(UserArchive {
+        profile,
+        comments: get_comment_by(repos.comments.as_ref(), username).await?,
+        sessions,
+        exported_at: chrono::Utc::now().to_rfc2822(),
+    })
+}
+
+pub async fn delete_user(repos: &Repositories, account: &AccountConfig, username: &str, password: &str) -> Result<DeleteReport, Box<dyn Error>> {
+    let user = get_user(repos.users.as_ref(), username).await?;
+    if !verify_helper(&user.permanent_token, password) {
+        return Err(Box::new(AuthError::WrongPassword));
+    }
+    let mut report = DeleteReport::default();
+    match account.comment_policy.unwrap_or(CommentPolicy::Anonymize) {
+        CommentPolicy::Delete => report.comments_deleted = delete_comment_by(repos.comments.as_ref(), username).await?,
+        CommentPolicy::Anonymize => report.comments_anonymized = anonymize_comment_by(repos.comments.as_ref(), username).await?,
+    }
+    repos.users.delete_user(doc! {"username": username}).await?;
+    report.sessions_revoked = revoke_sessions(username)?.len();
+    Ok(report)
 }
 
-pub async fn patch_user_handler(auth: BearerAuth, op: web::Json<PatchOperator>) -> impl Responder {
+pub async fn patch_user_handler(state: web::Data<AppState>, auth: BearerAuth, op: web::Json<PatchOperator>) -> impl Responder {
     let session = json_response!(get_session(auth).await).data.unwrap();
     let filter = doc! {"username": session.username};
-    web::Json(json_response!(patch_user(None, filter, op.0).await))
+    web::Json(json_response!(timed("patch_user", patch_user(state.repos.users.as_ref(), filter, op.0)).await))
+}
+
+async fn post_user_handler(state: web::Data<AppState>, req: web::Json<RegisterInfo>) -> impl Responder {
+    web::Json(json_response!(timed("post_user", post_user(&state.db, &state.policies.register, req.0)).await))
+}
+
+async fn get_user_handler(state: web::Data<AppState>, auth: BearerAuth) -> impl Responder {
+    let session = json_response!(get_session(auth).await).data.unwrap();
+    web::Json(json_response!(timed("get_user", get_user(state.repos.users.as_ref(), &session.username)).await))
 }
 
-async fn post_user_handler(req: web::Json<RegisterInfo>) -> impl Responder {
-    web::Json(json_response!(post_user(None, req.0).await))
+async fn get_available_handler(state: web::Data<AppState>, req: web::Query<AvailableQuery>) -> impl Responder {
+    web::Json(json_response!(timed("get_available", get_available(state.repos.users.as_ref(), &req.username)).await))
 }
 
-async fn get_user_handler(auth: BearerAuth) -> impl Responder {
+async fn export_user_handler(state: web::Data<AppState>, auth: BearerAuth) -> impl Responder {
     let session = json_response!(get_session(auth).await).data.unwrap();
-    web::Json(json_response!(get_user(None, &session.username).await))
+    web::Json(json_response!(timed("export_user", export_user(&state.repos, &session.username)).await))
+}
+
+async fn delete_user_handler(state: web::Data<AppState>, auth: BearerAuth, req: web::Json<DeleteConfirm>) -> impl Responder {
+    let session = json_response!(get_session(auth).await).data.unwrap();
+    web::Json(json_response!(timed("delete_user", delete_user(&state.repos, &state.policies.account, &session.username, &req.password)).await))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -97,30 +310,128 @@ pub fn config(cfg: &mut web::ServiceConfig) {
         web::resource("/user")
             .route(web::post().to(post_user_handler))
             .route(web::get().to(get_user_handler))
+            .route(web::delete().to(delete_user_handler))
+    );
+    cfg.service(
+        web::resource("/user/available")
+            .route(web::get().to(get_available_handler))
+    );
+    cfg.service(
+        web::resource("/user/export")
+            .route(web::get().to(export_user_handler))
     );
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
     use futures_await_test::async_test;
-    use mongodb::bson::doc;
+    use mongodb::bson::{Bson, doc};
     use rand::Rng;
     use uuid::Uuid;
 
-    use crate::resources::register_link::get_register_link;
-    use crate::resources::user::{post_user, RegisterInfo};
-    use crate::util::database::DEFAULT_DATABASE;
+    use crate::resources::register_link::{consume_code, get_register_link, restore_code};
+    use crate::resources::user::{check_patch, delete_user, export_user, get_user, post_user, RegisterInfo, User, UserProfile, validate_username};
+    use crate::util::config::{AccountConfig, CommentPolicy};
+    use crate::util::email_template::Language;
+    use crate::util::memory_store::MemoryStore;
+    use crate::util::ops::PatchOperator;
+    use crate::util::repository::Repositories;
+    use crate::util::testing::TestApp;
+
+    #[test]
+    fn test_validate_username() {
+        assert!(validate_username("flow_user-1").is_ok());
+        assert!(validate_username("流动用户").is_ok());
+        assert!(validate_username("ab").is_err());
+        assert!(validate_username("a".repeat(21).as_str()).is_err());
+        assert!(validate_username("bad name").is_err());
+        assert!(validate_username("Admin").is_err());
+    }
+
+    #[test]
+    fn test_profile_omits_password_hash() {
+        let profile = UserProfile::from(User {
+            username: "flow_user".to_string(),
+            email: "11712009@mail.sustech.edu.cn".to_string(),
+            permanent_token: "$2b$12$hash".to_string(),
+            learnt_course: vec!["CS101".to_string()],
+            admin: false,
+        });
+        let value = serde_json::to_value(&profile).unwrap();
+        assert!(value.get("permanent_token").is_none());
+        assert_eq!(value["learnt_course"][0], "CS101");
+    }
+
+    #[test]
+    fn test_check_patch() {
+        let course = || Bson::String("CS101".to_string());
+        assert!(check_patch(&PatchOperator::AddToSet("learnt_course".to_string(), course())).is_ok());
+        assert!(check_patch(&PatchOperator::RmFromSet("learnt_course".to_string(), course())).is_ok());
+        assert!(check_patch(&PatchOperator::Set("learnt_course".to_string(), Bson::Array(vec![course()]))).is_ok());
+        assert!(check_patch(&PatchOperator::Set("learnt_course".to_string(), Bson::Array(vec![Bson::Int32(1)]))).is_err());
+        assert!(check_patch(&PatchOperator::Inc("learnt_course".to_string(), 1)).is_err());
+        assert!(check_patch(&PatchOperator::Set("admin".to_string(), Bson::Boolean(true))).is_err());
+        assert!(check_patch(&PatchOperator::Set("permanent_token".to_string(), Bson::String("x".to_string()))).is_err());
+        assert!(check_patch(&PatchOperator::Set("email".to_string(), Bson::String("a@b.c".to_string()))).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_export_and_delete_in_memory() {
+        let store = Arc::new(MemoryStore::new());
+        store.insert("User", doc! {
+            "username": "leaving_user",
+            "email": "11111111@mail.sustech.edu.cn",
+            "permanent_token": bcrypt::hash("secret", 4).unwrap(),
+            "learnt_course": ["CS101"],
+        }).unwrap();
+        store.insert("Comment", doc! {
+            "gpa": "A", "cid": "CS101", "content": "good", "comment_by": "leaving_user", "term": "秋", "willing": true,
+            "anonymous": false, "rate": {"likes": 5.0, "useful": 4.0, "easy": 3.0, "ratings": 4.0}, "taught": ["Alice"],
+            "helpful": 0, "not_helpful": 0, "year": 2020, "month": 1, "day": 1,
+        }).unwrap();
+        let repos = Repositories::memory(store.clone());
+
+        let archive = export_user(&repos, "leaving_user").await.unwrap();
+        assert_eq!(archive.profile.learnt_course, vec!["CS101".to_string()]);
+        assert_eq!(archive.comments.len(), 1);
+
+        let account = AccountConfig { comment_policy: Some(CommentPolicy::Anonymize) };
+        assert!(delete_user(&repos, &account, "leaving_user", "wrong").await.is_err());
+        let report = delete_user(&repos, &account, "leaving_user", "secret").await.unwrap();
+        assert_eq!(report.comments_anonymized, 1);
+        assert!(store.find("User", &doc! {"username": "leaving_user"}).unwrap().is_empty());
+        assert!(store.find("Comment", &doc! {"comment_by": "leaving_user"}).unwrap().is_empty());
+        assert_eq!(store.find("Comment", &doc! {"cid": "CS101", "anonymous": true}).unwrap().len(), 1);
+    }
 
     #[async_test]
     async fn test_post_user() {
-        let username = Uuid::new_v4().to_string();
+        let app = TestApp::new().await.unwrap();
+        let username = Uuid::new_v4().to_simple().to_string()[..16].to_string();
         let mut rng = rand::thread_rng();
-        let email = (rng.gen_range(1000_0000, 9999_9999) as u32).to_string();
-        let code = get_register_link(&(email.clone() + "@sustech.edu.cn")).await.unwrap().code;
+        let email = format!("{}@sustech.edu.cn", rng.gen_range(1000_0000, 9999_9999) as u32);
+        get_register_link(app.db(), &app.state.email_sender, &app.state.policies.register, &email, Language::Zh).await.unwrap();
+        let code = app.last_code(&email).await.unwrap();
 
-        assert!(post_user(None, RegisterInfo { username: username.clone(), password: "test".to_string(), vcode: code, email }).await.is_ok());
-        let db = &DEFAULT_DATABASE;
-        assert!(db.cli.database(&db.name).collection("User")
-            .delete_one(doc! {"username": username}, None).await.is_ok());
+        // 地址大小写不同也是同一个邮箱，入库时统一为小写
+        let info = RegisterInfo { username: username.clone(), password: "test".to_string(), vcode: code, email: email.to_uppercase(), invite: None };
+        assert!(post_user(app.db(), &app.state.policies.register, info).await.is_ok());
+        assert_eq!(get_user(app.db(), &username).await.unwrap().email, email);
     }
-}
\ No newline at end of file
+
+    // 注册失败时放回的验证码可以再次使用
+    #[async_test]
+    async fn test_restore_code() {
+        let app = TestApp::new().await.unwrap();
+        let mut rng = rand::thread_rng();
+        let email = format!("{}@sustech.edu.cn", rng.gen_range(1000_0000, 9999_9999) as u32);
+        get_register_link(app.db(), &app.state.email_sender, &app.state.policies.register, &email, Language::Zh).await.unwrap();
+        let code = app.last_code(&email).await.unwrap();
+        let entry = consume_code(app.db(), &email, &code).await.unwrap();
+        assert!(consume_code(app.db(), &email, &code).await.is_err());
+        restore_code(app.db(), entry).await.unwrap();
+        assert!(consume_code(app.db(), &email, &code).await.is_ok());
+    }
+}
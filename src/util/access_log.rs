@@ -0,0 +1,177 @@
+use std::fmt;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_service::{Service, Transform};
+use actix_web::{Error, HttpMessage};
+use actix_web::dev::{Path, ServiceRequest, ServiceResponse, Url};
+use actix_web::http::header::{AUTHORIZATION, HeaderName, HeaderValue};
+use futures::future::{LocalBoxFuture, ok, Ready};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::resources::session::session_username;
+use crate::util::logger::json_enabled;
+use crate::util::metrics::observe_request;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// 只记录用户名，不记录 token
+#[derive(Debug, Serialize)]
+pub struct AccessRecord {
+    request_id: String,
+    method: String,
+    path: String,
+    status: u16,
+    latency_ms: f64,
+    username: Option<String>,
+}
+
+impl fmt::Display for AccessRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {} {:.1}ms user={} request_id={}",
+               self.method, self.path, self.status, self.latency_ms,
+               self.username.as_deref().unwrap_or("-"), self.request_id)
+    }
+}
+
+fn bearer_username(req: &ServiceRequest) -> Option<String> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let token = header.splitn(2, ' ').nth(1)?.trim();
+    session_username(token)
+}
+
+// 上游已经带了请求 id 时沿用，便于跨服务关联；过长或含其他字符的会污染日志，重新生成
+fn request_id(header: Option<&HeaderValue>) -> String {
+    header
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+// actix-web 2 拿不到匹配的路由模板，用路径参数反推，避免 /course/{cid} 按 cid 展开成大量标签
+fn route_pattern(info: &Path<Url>, path: &str, status: u16) -> String {
+    if status == 404 && info.is_empty() {
+        return "unmatched".to_string();
+    }
+    let params = info.iter().collect::<Vec<(&str, &str)>>();
+    path.split('/')
+        .map(|segment| match params.iter().find(|(_, value)| *value == segment) {
+            Some((name, _)) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+pub struct AccessLog;
+
+impl<S, B> Transform<S> for AccessLog
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogMiddleware { service })
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for AccessLogMiddleware<S>
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let request_id = request_id(req.headers().get(REQUEST_ID_HEADER));
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+        let mut record = AccessRecord {
+            request_id,
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            status: 0,
+            latency_ms: 0.0,
+            username: bearer_username(&req),
+        };
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let result = future.await;
+            record.latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+            let (status, route) = match &result {
+                Ok(res) => (res.status().as_u16(), route_pattern(res.request().match_info(), &record.path, res.status().as_u16())),
+                Err(err) => (err.as_response_error().status_code().as_u16(), "error".to_string()),
+            };
+            record.status = status;
+            observe_request(&record.method, &route, status, record.latency_ms / 1000.0);
+            if json_enabled() {
+                log::info!(target: "access", "{}", serde_json::to_string(&record).unwrap_or_default());
+            } else {
+                log::info!(target: "access", "{}", record);
+            }
+            let mut res = result?;
+            if let Ok(value) = HeaderValue::from_str(&record.request_id) {
+                res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::dev::{Path, Url};
+    use actix_web::http::header::HeaderValue;
+    use actix_web::http::Uri;
+
+    use crate::util::access_log::{request_id, route_pattern};
+
+    #[test]
+    fn test_route_pattern() {
+        let mut info = Path::new(Url::new(Uri::from_static("/course/CS101")));
+        info.add_static("cid", "CS101");
+        assert_eq!(route_pattern(&info, "/course/CS101", 200), "/course/{cid}");
+        let empty = Path::new(Url::new(Uri::from_static("/nothing")));
+        assert_eq!(route_pattern(&empty, "/nothing", 404), "unmatched");
+        assert_eq!(route_pattern(&empty, "/course", 200), "/course");
+    }
+
+    #[test]
+    fn test_request_id() {
+        let id = "3f2c7a9e-1b2d-4c5e-8f90-a1b2c3d4e5f6";
+        assert_eq!(request_id(Some(&HeaderValue::from_static(id))), id);
+        for bad in &["", "a b", "id\u{7f}", "{\"status\":500}", &"a".repeat(65)] {
+            if let Ok(value) = HeaderValue::from_str(bad) {
+                assert_ne!(request_id(Some(&value)), *bad);
+            }
+        }
+        assert_eq!(request_id(None).len(), 36);
+    }
+}
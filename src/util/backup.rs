@@ -0,0 +1,294 @@
+use core::fmt;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::stream::StreamExt;
+use mongodb::bson::{Bson, doc, Document, from_bson};
+use mongodb::options::ReplaceOptions;
+use serde::{Deserialize, Serialize};
+
+use crate::resources::comment::Comment;
+use crate::resources::course::CourseSection;
+use crate::resources::detail::Detail;
+use crate::resources::rate::Rate;
+use crate::resources::user::User;
+use crate::util::database::Database;
+use crate::util::migration;
+
+pub const COLLECTIONS: [&str; 5] = ["User", "Comment", "Course", "Detail", "Rate"];
+const BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupHeader {
+    // 导出时数据库已执行的最新迁移版本，只能恢复到同一版本的数据库
+    migration_version: u32,
+    collection: String,
+    exported_at: String,
+}
+
+#[derive(Debug)]
+pub enum BackupError {
+    UnknownCollection(String),
+    MissingFile(String),
+    BadHeader(String),
+    VersionMismatch(String, u32, u32),
+    InvalidDocument(String, usize, String),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackupError::UnknownCollection(name) => write!(f, "unknown collection {}", name),
+            BackupError::MissingFile(name) => write!(f, "no backup file for collection {}", name),
+            BackupError::BadHeader(name) => write!(f, "backup of {} has a malformed header", name),
+            BackupError::VersionMismatch(name, version, expected) =>
+                write!(f, "backup of {} was taken at migration version {}, but the database is at version {}", name, version, expected),
+            BackupError::InvalidDocument(name, line, err) => write!(f, "{} line {} is invalid: {}", name, line, err),
+        }
+    }
+}
+
+impl Error for BackupError {}
+
+#[derive(Debug, Serialize, Default)]
+pub struct BackupReport {
+    collection: String,
+    documents: usize,
+}
+
+fn validate(collection: &str, document: Bson) -> Result<(), Box<dyn Error>> {
+    match collection {
+        "User" => { from_bson::<User>(document)?; }
+        "Comment" => { from_bson::<Comment>(document)?; }
+        "Course" => { from_bson::<CourseSection>(document)?; }
+        "Detail" => { from_bson::<Detail>(document)?; }
+        "Rate" => { from_bson::<Rate>(document)?; }
+        _ => return Err(Box::new(BackupError::UnknownCollection(collection.to_string()))),
+    }
+    Ok(())
+}
+
+fn select(collections: &[String]) -> Result<Vec<&'static str>, BackupError> {
+    if collections.is_empty() {
+        return Ok(COLLECTIONS.to_vec());
+    }
+    collections
+        .iter()
+        .map(|name| COLLECTIONS
+            .iter()
+            .find(|c| c.eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or_else(|| BackupError::UnknownCollection(name.clone())))
+        .collect()
+}
+
+fn backup_path(dir: &Path, collection: &str, gzip: bool) -> PathBuf {
+    dir.join(format!("{}.ndjson{}", collection, if gzip { ".gz" } else { "" }))
+}
+
+pub async fn export(db: &Database, dir: &Path, collections: &[String], gzip: bool) -> Result<Vec<BackupReport>, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    let version = migration::current_version(db).await?;
+    let mut reports = vec![];
+    for collection in select(collections)? {
+        let file = File::create(backup_path(dir, collection, gzip))?;
+        let mut writer: Box<dyn Write> = if gzip {
+            Box::new(BufWriter::new(GzEncoder::new(file, Compression::default())))
+        } else {
+            Box::new(BufWriter::new(file))
+        };
+        let header = BackupHeader {
+            migration_version: version,
+            collection: collection.to_string(),
+            exported_at: Utc::now().to_rfc3339(),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+
+        let mut cursor = db.cli.database(&db.name).collection(collection).find(doc! {}, None).await?;
+        let mut report = BackupReport { collection: collection.to_string(), documents: 0 };
+        while let Some(document) = cursor.next().await {
+            writeln!(writer, "{}", Bson::Document(document?).into_canonical_extjson())?;
+            report.documents += 1;
+        }
+        writer.flush()?;
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+fn parse_line(collection: &str, line: &str) -> Result<Document, Box<dyn Error>> {
+    let document = serde_json::from_str::<Bson>(line)?;
+    validate(collection, document.clone())?;
+    match document {
+        Bson::Document(document) => Ok(document),
+        _ => Err("not a document".into()),
+    }
+}
+
+// 逐行读取，不把整个文件读进内存；表头的迁移版本必须和数据库一致
+fn read_backup(dir: &Path, collection: &'static str, version: u32) -> Result<impl Iterator<Item=Result<Document, Box<dyn Error>>>, Box<dyn Error>> {
+    let reader: Box<dyn BufRead> = if backup_path(dir, collection, false).exists() {
+        Box::new(BufReader::new(File::open(backup_path(dir, collection, false))?))
+    } else if backup_path(dir, collection, true).exists() {
+        Box::new(BufReader::new(GzDecoder::new(File::open(backup_path(dir, collection, true))?)))
+    } else {
+        return Err(Box::new(BackupError::MissingFile(collection.to_string())));
+    };
+    let mut lines = reader.lines();
+    let header = lines.next().ok_or_else(|| BackupError::BadHeader(collection.to_string()))??;
+    let header = serde_json::from_str::<BackupHeader>(&header)
+        .map_err(|_| BackupError::BadHeader(collection.to_string()))?;
+    if header.collection != collection {
+        return Err(Box::new(BackupError::BadHeader(collection.to_string())));
+    }
+    if header.migration_version != version {
+        return Err(Box::new(BackupError::VersionMismatch(collection.to_string(), header.migration_version, version)));
+    }
+
+    Ok(lines.enumerate().filter_map(move |(i, line)| {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => return Some(Err(Box::new(err) as Box<dyn Error>)),
+        };
+        if line.trim().is_empty() {
+            return None;
+        }
+        Some(parse_line(collection, &line).map_err(|err| {
+            Box::new(BackupError::InvalidDocument(collection.to_string(), i + 2, err.to_string())) as Box<dyn Error>
+        }))
+    }))
+}
+
+pub async fn restore(db: &Database, dir: &Path, collections: &[String], drop: bool) -> Result<Vec<BackupReport>, Box<dyn Error>> {
+    let selected = select(collections)?;
+    let version = migration::current_version(db).await?;
+    // 第一遍只校验所有文件，任何一条记录不合法都不写入；第二遍再逐批写入
+    for collection in &selected {
+        for document in read_backup(dir, collection, version)? {
+            document?;
+        }
+    }
+
+    let mut reports = vec![];
+    for collection in selected {
+        let coll = db.cli.database(&db.name).collection(collection);
+        let mut report = BackupReport { collection: collection.to_string(), documents: 0 };
+        if drop {
+            coll.delete_many(doc! {}, None).await?;
+        }
+        let mut batch = vec![];
+        for document in read_backup(dir, collection, version)? {
+            let document = document?;
+            report.documents += 1;
+            if drop {
+                batch.push(document);
+                if batch.len() >= BATCH_SIZE {
+                    coll.insert_many(std::mem::take(&mut batch), None).await?;
+                }
+                continue;
+            }
+            match document.get("_id").cloned() {
+                Some(id) => {
+                    coll.replace_one(doc! {"_id": id}, document, ReplaceOptions::builder().upsert(true).build())
+                        .await?;
+                }
+                None => { coll.insert_one(document, None).await?; }
+            }
+        }
+        if !batch.is_empty() {
+            coll.insert_many(batch, None).await?;
+        }
+        reports.push(report);
+    }
+    Ok(reports)
+}
+
+fn usage(command: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, match command {
+        "export" => "usage: server_v2 export <dir> [--gzip] [--collections User,Comment,...]",
+        _ => "usage: server_v2 restore <dir> [--drop] [--collections User,Comment,...]",
+    })
+}
+
+pub async fn run_command(db: &Database, command: &str, args: &[String]) -> io::Result<()> {
+    let mut dir = None;
+    let mut collections = vec![];
+    let mut flag = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--gzip" if command == "export" => flag = true,
+            "--drop" if command == "restore" => flag = true,
+            "--collections" => collections = args
+                .next()
+                .ok_or_else(|| usage(command))?
+                .split(',')
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect(),
+            _ if dir.is_none() => dir = Some(PathBuf::from(arg)),
+            _ => return Err(usage(command)),
+        }
+    }
+    let dir = dir.ok_or_else(|| usage(command))?;
+    let reports = if command == "export" {
+        export(db, &dir, &collections, flag).await
+    } else {
+        restore(db, &dir, &collections, flag).await
+    }.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    println!("{}", serde_json::to_string_pretty(&reports)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Write;
+
+    use mongodb::bson::{Bson, doc};
+    use uuid::Uuid;
+
+    use crate::util::backup::{backup_path, read_backup, select, validate};
+
+    #[test]
+    fn test_select_collections() {
+        assert_eq!(select(&[]).unwrap().len(), 5);
+        assert_eq!(select(&["user".to_string()]).unwrap(), vec!["User"]);
+        assert!(select(&["Session".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_extjson_round_trip_validates() {
+        let rate = doc! {"cid": "CS101", "name": "计算机导论", "ratings": 10.0, "likes": 4.5, "useful": 4.0, "easy": 3.0};
+        let line = Bson::Document(rate).into_canonical_extjson().to_string();
+        let parsed = serde_json::from_str::<Bson>(&line).unwrap();
+        assert!(validate("Rate", parsed.clone()).is_ok());
+        assert!(validate("Detail", parsed).is_err());
+    }
+
+    #[test]
+    fn test_read_backup_checks_version_and_streams() {
+        let dir = std::env::temp_dir().join(format!("flow_backup_{}", Uuid::new_v4().to_simple()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = fs::File::create(backup_path(&dir, "Rate", false)).unwrap();
+        writeln!(file, "{}", r#"{"migration_version":7,"collection":"Rate","exported_at":"2020-01-01T00:00:00Z"}"#).unwrap();
+        let rate = doc! {"cid": "CS101", "name": "计算机导论", "ratings": 10.0, "likes": 4.5, "useful": 4.0, "easy": 3.0};
+        writeln!(file, "{}\n", Bson::Document(rate).into_canonical_extjson()).unwrap();
+        writeln!(file, "{}", r#"{"cid":"CS102"}"#).unwrap();
+        drop(file);
+
+        assert!(read_backup(&dir, "Rate", 6).is_err());
+        let documents = read_backup(&dir, "Rate", 7).unwrap().collect::<Vec<_>>();
+        assert_eq!(documents.len(), 2);
+        assert_eq!(documents[0].as_ref().unwrap().get_str("cid").unwrap(), "CS101");
+        assert!(documents[1].as_ref().unwrap_err().to_string().contains("line 4"));
+        assert!(read_backup(&dir, "Detail", 7).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
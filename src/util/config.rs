@@ -1,10 +1,10 @@
 extern crate toml;
 
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 
-use lazy_static::lazy_static;
 use serde::{de, Deserialize, Serialize};
 
 use crate::error;
@@ -16,28 +16,99 @@ pub struct DatabaseConfig {
     pub(crate) port: Option<u16>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    Smtp,
+    File,
+    Memory,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    None,
+    StartTls,
+    Tls,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct EmailSenderConfig {
     pub(crate) smtp_server: Option<String>,
     pub(crate) smtp_account: Option<String>,
     pub(crate) smtp_password: Option<String>,
     pub(crate) smtp_port: Option<u16>,
+    pub(crate) smtp_tls: Option<SmtpTls>,
+    pub(crate) transport: Option<TransportKind>,
+    pub(crate) file_dir: Option<String>,
+    pub(crate) base_url: Option<String>,
 }
 
-lazy_static! {
-    pub static ref DEFAULT_DATABASE_CONFIG: DatabaseConfig = sync_new("config/Database.toml")
-        .expect("at least one default database config is needed");
-    pub static ref DEFAULT_EMAIL_SENDER_CONFIG: EmailSenderConfig = sync_new("config/EmailSender.toml")
-        .expect("at least one default email config is needed");
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommentPolicy {
+    Delete,
+    Anonymize,
 }
 
-pub fn sync_new<T>(filepath: &str) -> Result<T, Box<dyn Error>>
-    where T: de::DeserializeOwned
-{
-    let mut config_str = String::new();
-    File::open(filepath)?
-        .read_to_string(&mut config_str)?;
-    error!(toml::from_str(&config_str))
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AccountConfig {
+    pub(crate) comment_policy: Option<CommentPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StaffPolicy {
+    Allow,
+    InviteOnly,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RegisterConfig {
+    pub(crate) domains: Option<Vec<String>>,
+    pub(crate) local_part: Option<String>,
+    pub(crate) exceptions: Option<Vec<String>>,
+    pub(crate) staff: Option<StaffPolicy>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LogConfig {
+    pub(crate) level: Option<String>,
+    pub(crate) format: Option<LogFormat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HealthConfig {
+    pub(crate) check_smtp: Option<bool>,
+    pub(crate) timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SecurityConfig {
+    pub(crate) allowed_origins: Option<Vec<String>>,
+    pub(crate) allowed_methods: Option<Vec<String>>,
+    pub(crate) allowed_headers: Option<Vec<String>>,
+    pub(crate) allow_credentials: Option<bool>,
+    pub(crate) max_age: Option<u32>,
+    pub(crate) hsts_max_age: Option<u32>,
+    pub(crate) json_limit: Option<usize>,
+    pub(crate) payload_limits: Option<BTreeMap<String, usize>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CommentConfig {
+    pub(crate) min_length: Option<usize>,
+    pub(crate) max_length: Option<usize>,
+    pub(crate) rating_min: Option<f32>,
+    pub(crate) rating_max: Option<f32>,
+    pub(crate) earliest_year: Option<i32>,
 }
 
 pub async fn new<T>(filepath: &str) -> Result<T, Box<dyn Error>>
@@ -48,16 +119,26 @@ pub async fn new<T>(filepath: &str) -> Result<T, Box<dyn Error>>
         .read_to_string(&mut config_str)?;
     error!(toml::from_str(&config_str))
 }
+
+// 可选的配置文件不存在时用默认值，存在但读不了或格式不对时报错
+pub async fn optional<T>(filepath: &str) -> Result<T, Box<dyn Error>>
+    where T: de::DeserializeOwned + Default
+{
+    match File::open(filepath) {
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+        _ => new(filepath).await,
+    }
+}
 #[cfg(test)]
 mod test {
     use futures_await_test::async_test;
 
-    use crate::util::config::{DatabaseConfig, EmailSenderConfig, new};
+    use crate::util::config::{AccountConfig, CommentConfig, CommentPolicy, DatabaseConfig, EmailSenderConfig, HealthConfig, LogConfig, LogFormat, new, optional, RegisterConfig, SecurityConfig, SmtpTls, StaffPolicy, TransportKind};
 
     #[async_test]
     async fn test_load_email_sender_config() {
         let config = new::<EmailSenderConfig>("config/EmailSender.toml").await;
-        if let Ok(EmailSenderConfig { smtp_server: Some(smtp_server), smtp_account: Some(smtp_account), smtp_password: Some(_), smtp_port: Some(smtp_port) }) = config {
+        if let Ok(EmailSenderConfig { smtp_server: Some(smtp_server), smtp_account: Some(smtp_account), smtp_password: Some(_), smtp_port: Some(smtp_port), .. }) = config {
             assert_eq!(smtp_server, "smtpdm.aliyun.com");
             assert_eq!(smtp_account, "regsiterlink@auto.sustechflow.top");
             assert_eq!(smtp_port, 80);
@@ -66,6 +147,71 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_parse_email_transport() {
+        let config = toml::from_str::<EmailSenderConfig>("transport=\"file\"\nfile_dir=\"mail\"\nsmtp_tls=\"starttls\"").unwrap();
+        assert_eq!(config.transport, Some(TransportKind::File));
+        assert_eq!(config.smtp_tls, Some(SmtpTls::StartTls));
+        assert_eq!(config.file_dir, Some("mail".to_string()));
+    }
+
+    #[async_test]
+    async fn test_load_account_config() {
+        let config = new::<AccountConfig>("config/Account.toml").await;
+        if let Ok(AccountConfig { comment_policy: Some(policy) }) = config {
+            assert_eq!(policy, CommentPolicy::Anonymize);
+        } else {
+            panic!("fields are missing, failed")
+        }
+    }
+
+    #[async_test]
+    async fn test_load_register_config() {
+        let config = new::<RegisterConfig>("config/Register.toml").await;
+        if let Ok(RegisterConfig { domains: Some(domains), local_part: Some(_), staff: Some(staff), .. }) = config {
+            assert!(domains.contains(&"mail.sustech.edu.cn".to_string()));
+            assert_eq!(staff, StaffPolicy::InviteOnly);
+        } else {
+            panic!("fields are missing, failed")
+        }
+    }
+
+    #[async_test]
+    async fn test_load_log_config() {
+        let config = new::<LogConfig>("config/Log.toml").await;
+        if let Ok(LogConfig { level: Some(level), format: Some(format) }) = config {
+            assert_eq!(level, "info");
+            assert_eq!(format, LogFormat::Text);
+        } else {
+            panic!("fields are missing, failed")
+        }
+    }
+
+    #[async_test]
+    async fn test_load_comment_config() {
+        let config = new::<CommentConfig>("config/Comment.toml").await.unwrap();
+        assert_eq!(config.max_length, Some(2000));
+        assert_eq!(config.rating_max, Some(5.0));
+    }
+
+    #[async_test]
+    async fn test_load_security_config() {
+        let config = new::<SecurityConfig>("config/Security.toml").await.unwrap();
+        assert!(config.allowed_origins.unwrap().contains(&"https://sustechflow.top".to_string()));
+        assert_eq!(config.allow_credentials, Some(true));
+        assert_eq!(config.payload_limits.unwrap().get("/user"), Some(&4096));
+    }
+
+    #[async_test]
+    async fn test_load_optional_config() {
+        let config = optional::<HealthConfig>("config/Missing.toml").await.unwrap();
+        assert_eq!(config.check_smtp, None);
+        let path = std::env::temp_dir().join(format!("flow_config_{}.toml", std::process::id()));
+        std::fs::write(&path, "json_limit = \"big\"").unwrap();
+        assert!(optional::<SecurityConfig>(path.to_str().unwrap()).await.is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[async_test]
     async fn test_load_database_config() {
         let config = new::<DatabaseConfig>("config/DatabaseTest.toml").await;
@@ -1,22 +1,18 @@
 use std::error::Error;
 
-use async_std::task::block_on;
-use lazy_static::lazy_static;
+use mongodb::bson::doc;
 use mongodb::Client;
 
 use crate::{error, util::{config::DatabaseConfig}};
+use crate::util::index::{ensure_indexes, IndexReport};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Database {
     pub name: String,
     pub(crate) ip: String,
     pub(crate) port: u16,
     pub cli: Client,
-}
-
-lazy_static! {
-    pub static ref DEFAULT_DATABASE: Database = Database::sync_new(None)
-        .expect("at least one usable database is needed");
+    pub index_report: IndexReport,
 }
 
 impl DatabaseConfig {
@@ -26,30 +22,31 @@ impl DatabaseConfig {
 }
 
 impl Database {
-    pub async fn new(config: Option<&DatabaseConfig>) -> Result<Database, Box<dyn Error>> {
-        use crate::util::config::DEFAULT_DATABASE_CONFIG;
-        let config = config.unwrap_or(&*DEFAULT_DATABASE_CONFIG);
-        Ok(Database {
+    pub async fn new(config: &DatabaseConfig) -> Result<Database, Box<dyn Error>> {
+        let mut db = Database {
             name: config.name.as_ref().ok_or("name is missing")?.clone(),
             ip: config.ip.as_ref().ok_or("ip is missing")?.clone(),
             port: config.port.as_ref().ok_or("port is missing")?.clone(),
-            cli: config.connect().await?
-        })
-    }
-
-    pub fn sync_new(config: Option<&DatabaseConfig>) -> Result<Database, Box<dyn Error>> {
-        block_on(Database::new(config))
+            cli: config.connect().await?,
+            index_report: IndexReport::default(),
+        };
+        // 客户端是惰性连接的，先 ping 一次确认 MongoDB 可用
+        db.cli.database(&db.name).run_command(doc! {"ping": 1}, None).await?;
+        db.index_report = ensure_indexes(&db, &crate::resources::indexes()).await?;
+        Ok(db)
     }
 }
 #[cfg(test)]
 mod test {
     use futures_await_test::async_test;
 
+    use crate::util::config::{DatabaseConfig, new};
     use crate::util::database::Database;
 
     #[async_test]
     async fn test_database_connect() {
-        let db = Database::new(None).await.unwrap();
+        let config = new::<DatabaseConfig>("config/Database.toml").await.unwrap();
+        let db = Database::new(&config).await.unwrap();
         for _ in 1..10 {
             let cli = &db.cli;
             assert!(cli.list_database_names(None, None).await.unwrap().contains(&db.name));
@@ -1,75 +1,267 @@
 use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-use async_std::task::block_on;
-use lazy_static::lazy_static;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use chrono::Utc;
 use lettre::{Message, SmtpTransport, Tls, Transport};
+use lettre::message::MultiPart;
 use lettre::transport::smtp::authentication::Credentials;
-use lettre::transport::smtp::response::Response;
+use lettre::transport::smtp::client::TlsParameters;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::util::config::EmailSenderConfig;
+use crate::util::config::{EmailSenderConfig, SmtpTls, TransportKind};
+use crate::util::email_template::{Language, render, Template};
 
-pub struct EmailSender {
+const DEFAULT_FROM: &str = "flow@localhost";
+const DEFAULT_MAIL_DIR: &str = "mail";
+const DEFAULT_BASE_URL: &str = "https://sustechflow.top";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    #[serde(default)]
+    pub html: Option<String>,
+}
+
+impl Email {
+    fn encode_header(value: &str) -> String {
+        if value.is_ascii() {
+            value.to_string()
+        } else {
+            format!("=?UTF-8?B?{}?=", base64::encode(value))
+        }
+    }
+
+    fn encode_part(content_type: &str, body: &str) -> String {
+        format!(
+            "Content-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n{}\r\n",
+            content_type,
+            base64::encode(body),
+        )
+    }
+
+    pub fn to_eml(&self, from: &str) -> String {
+        let content = match &self.html {
+            Some(html) => {
+                let boundary = Uuid::new_v4().to_simple().to_string();
+                format!(
+                    "Content-Type: multipart/alternative; boundary=\"{0}\"\r\n\r\n--{0}\r\n{1}--{0}\r\n{2}--{0}--\r\n",
+                    boundary,
+                    Email::encode_part("text/plain", &self.body),
+                    Email::encode_part("text/html", html),
+                )
+            }
+            None => Email::encode_part("text/plain", &self.body),
+        };
+        format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n{}",
+            from,
+            self.to,
+            Email::encode_header(&self.subject),
+            Utc::now().to_rfc2822(),
+            content,
+        )
+    }
+}
+
+pub trait EmailTransport: Send + Sync {
+    fn send(&self, from: &str, email: &Email) -> Result<(), Box<dyn Error>>;
+
+    // 只有内存后端会记录发出的邮件，供测试读取
+    fn sent(&self) -> Vec<Email> {
+        vec![]
+    }
+}
+
+pub struct SmtpEmailTransport {
     pub(crate) smtp_server: String,
-    pub(crate) smtp_account: String,
     pub(crate) smtp_port: u16,
     pub(crate) cred: Credentials,
+    pub(crate) tls: SmtpTls,
 }
 
-lazy_static! {
-    pub static ref DEFAULT_EMAIL_SENDER: EmailSender = EmailSender::sync_new(None)
-            .expect("at least one usable email sender is needed");
+impl EmailTransport for SmtpEmailTransport {
+    fn send(&self, from: &str, email: &Email) -> Result<(), Box<dyn Error>> {
+        let builder = Message::builder()
+            .from(from.parse()?)
+            .to(email.to.parse()?)
+            .subject(email.subject.as_str());
+        let message = match &email.html {
+            Some(html) => builder.multipart(MultiPart::alternative_plain_html(email.body.clone(), html.clone()))?,
+            None => builder.body(email.body.as_str())?,
+        };
+        let tls = match self.tls {
+            SmtpTls::None => Tls::None,
+            SmtpTls::StartTls => Tls::Required(TlsParameters::new(self.smtp_server.clone())?),
+            SmtpTls::Tls => Tls::Wrapper(TlsParameters::new(self.smtp_server.clone())?),
+        };
+        let mailer = SmtpTransport::relay(&self.smtp_server)?
+            .port(self.smtp_port)
+            .credentials(self.cred.clone())
+            .tls(tls)
+            .build();
+        mailer.send(&message)?;
+        Ok(())
+    }
 }
 
-impl EmailSender {
-    pub async fn new(config: Option<&EmailSenderConfig>) -> Result<EmailSender, Box<dyn Error>> {
-        use crate::util::config::DEFAULT_EMAIL_SENDER_CONFIG;
-        let config = config.unwrap_or(&*DEFAULT_EMAIL_SENDER_CONFIG);
+pub struct FileEmailTransport {
+    pub(crate) dir: PathBuf,
+}
 
-        let smtp_account = config.smtp_account.as_ref().ok_or("smtp_account is missing")?.clone();
-        let smtp_password = config.smtp_password.as_ref().ok_or("smtp_password is missing")?.clone();
-        let smtp_server = config.smtp_server.as_ref().ok_or("smtp_server is missing")?.clone();
-        let smtp_port = config.smtp_port.as_ref().ok_or("smtp_port is missing")?.clone();
+impl EmailTransport for FileEmailTransport {
+    fn send(&self, from: &str, email: &Email) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let name = format!("{}-{}.eml", Utc::now().format("%Y%m%d%H%M%S"), Uuid::new_v4());
+        fs::write(self.dir.join(name), email.to_eml(from))?;
+        Ok(())
+    }
+}
 
-        let cred = Credentials::new(smtp_account.clone(), smtp_password);
+#[derive(Default)]
+pub struct MemoryEmailTransport {
+    outbox: Mutex<Vec<Email>>,
+}
 
-        Ok(EmailSender {
-            smtp_server,
-            smtp_account,
-            smtp_port,
-            cred,
-        })
+impl EmailTransport for MemoryEmailTransport {
+    fn send(&self, _from: &str, email: &Email) -> Result<(), Box<dyn Error>> {
+        self.outbox.lock().map_err(|e| e.to_string())?.push(email.clone());
+        Ok(())
     }
 
-    pub fn sync_new(config: Option<&EmailSenderConfig>) -> Result<EmailSender, Box<dyn Error>> {
-        block_on(EmailSender::new(config))
+    fn sent(&self) -> Vec<Email> {
+        self.outbox.lock().map(|outbox| outbox.clone()).unwrap_or_default()
     }
 }
 
+pub struct EmailSender {
+    pub(crate) from: String,
+    pub(crate) base_url: String,
+    pub(crate) transport: Arc<dyn EmailTransport>,
+}
+
 impl EmailSender {
-    pub async fn send(&self, recv_addr: &str, subject: &str, body: &str) -> Result<Response, Box<dyn Error>> {
-        let email = Message::builder()
-            .from(self.smtp_account.parse()?)
-            .to(recv_addr.parse()?)
-            .subject(subject)
-            .body(body)?;
-        let mailer = SmtpTransport::relay(&self.smtp_server)?
-            .port(self.smtp_port)
-            .credentials(self.cred.clone())
-            .tls(Tls::None)
-            .build();
-        Ok(mailer.send(&email)?)
+    pub async fn new(config: &EmailSenderConfig) -> Result<EmailSender, Box<dyn Error>> {
+        let from = config.smtp_account.clone().unwrap_or_else(|| DEFAULT_FROM.to_string());
+        let transport: Arc<dyn EmailTransport> = match config.transport.unwrap_or(TransportKind::Smtp) {
+            TransportKind::Smtp => {
+                let smtp_account = config.smtp_account.as_ref().ok_or("smtp_account is missing")?.clone();
+                let smtp_password = config.smtp_password.as_ref().ok_or("smtp_password is missing")?.clone();
+                let smtp_server = config.smtp_server.as_ref().ok_or("smtp_server is missing")?.clone();
+                let smtp_port = config.smtp_port.as_ref().ok_or("smtp_port is missing")?.clone();
+                Arc::new(SmtpEmailTransport {
+                    smtp_server,
+                    smtp_port,
+                    cred: Credentials::new(smtp_account, smtp_password),
+                    tls: config.smtp_tls.unwrap_or(SmtpTls::None),
+                })
+            }
+            TransportKind::File => Arc::new(FileEmailTransport {
+                dir: PathBuf::from(config.file_dir.clone().unwrap_or_else(|| DEFAULT_MAIL_DIR.to_string())),
+            }),
+            TransportKind::Memory => Arc::new(MemoryEmailTransport::default()),
+        };
+
+        let base_url = config.base_url.clone().unwrap_or_else(|| DEFAULT_BASE_URL.to_string());
+        Ok(EmailSender { from, base_url, transport })
+    }
+
+    pub fn with_transport(from: &str, transport: Arc<dyn EmailTransport>) -> EmailSender {
+        EmailSender { from: from.to_string(), base_url: DEFAULT_BASE_URL.to_string(), transport }
+    }
+}
+
+impl EmailSender {
+    pub async fn send(&self, recv_addr: &str, subject: &str, body: &str) -> Result<(), Box<dyn Error>> {
+        self.send_blocking(Email {
+            to: recv_addr.to_string(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            html: None,
+        }).await
+    }
+
+    // 模板中的 {base_url} 由配置填充，其余变量由调用方给出
+    pub fn render(&self, recv_addr: &str, template: Template, lang: Language, vars: &[(&str, &str)]) -> Email {
+        let rendered = render(template, lang, &self.base_url, vars);
+        Email {
+            to: recv_addr.to_string(),
+            subject: rendered.subject,
+            body: rendered.text,
+            html: Some(rendered.html),
+        }
+    }
+
+    pub async fn send_template(&self, recv_addr: &str, template: Template, lang: Language, vars: &[(&str, &str)]) -> Result<(), Box<dyn Error>> {
+        self.send_blocking(self.render(recv_addr, template, lang, vars)).await
+    }
+
+    // SMTP 发送是阻塞的，放到线程池中执行以免占住 actix worker
+    pub async fn send_blocking(&self, email: Email) -> Result<(), Box<dyn Error>> {
+        let from = self.from.clone();
+        let transport = self.transport.clone();
+        web::block(move || transport.send(&from, &email).map_err(|e| e.to_string()))
+            .await
+            .map_err(|e| match e {
+                BlockingError::Error(e) => e,
+                BlockingError::Canceled => "email sending was canceled".to_string(),
+            })?;
+        Ok(())
+    }
+
+    pub fn sent(&self) -> Vec<Email> {
+        self.transport.sent()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
     use futures_await_test::async_test;
+    use uuid::Uuid;
 
-    use crate::util::email_sender::EmailSender;
+    use crate::util::email_sender::{EmailSender, FileEmailTransport, MemoryEmailTransport};
+    use crate::util::email_template::{Language, Template};
 
     #[async_test]
     async fn test_email_send() {
-        let sender = EmailSender::new(None).await.unwrap();
+        let sender = EmailSender::with_transport("flow@localhost", Arc::new(MemoryEmailTransport::default()));
         sender.send("11712009@mail.sustech.edu.cn", "test", "test").await.unwrap();
+        let sent = sender.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "11712009@mail.sustech.edu.cn");
     }
-}
\ No newline at end of file
+
+    #[async_test]
+    async fn test_file_email_send() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let sender = EmailSender::with_transport("flow@localhost", Arc::new(FileEmailTransport { dir: dir.clone() }));
+        sender.send("11712009@mail.sustech.edu.cn", "注册链接", "test").await.unwrap();
+        let files = fs::read_dir(&dir).unwrap().map(|f| f.unwrap().path()).collect::<Vec<PathBuf>>();
+        assert_eq!(files.len(), 1);
+        let eml = fs::read_to_string(&files[0]).unwrap();
+        assert!(eml.contains("To: 11712009@mail.sustech.edu.cn"));
+        assert!(eml.contains("Subject: =?UTF-8?B?"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_test]
+    async fn test_template_email_send() {
+        let sender = EmailSender::with_transport("flow@localhost", Arc::new(MemoryEmailTransport::default()));
+        sender.send_template("11712009@mail.sustech.edu.cn", Template::Registration, Language::En, &[("code", "abc")]).await.unwrap();
+        let sent = sender.sent();
+        assert_eq!(sent[0].subject, "Your Flow sign-up link");
+        assert!(sent[0].body.contains("https://sustechflow.top/signup?vcode=abc"));
+        assert!(sent[0].html.as_ref().unwrap().contains("<a href="));
+        assert!(sent[0].to_eml("flow@localhost").contains("multipart/alternative"));
+    }
+}
@@ -0,0 +1,176 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    Zh,
+    En,
+}
+
+impl Default for Language {
+    fn default() -> Self {
+        Language::Zh
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Template {
+    Registration,
+    PasswordReset,
+    NotificationDigest,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RenderedEmail {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+struct Source {
+    subject: &'static str,
+    text: &'static str,
+    html: &'static str,
+}
+
+const LAYOUT: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\"></head>\
+<body style=\"font-family:sans-serif;color:#333\">{content}\
+<p style=\"color:#999;font-size:12px\">SUSTechFlow · <a href=\"{base_url}\">{base_url}</a></p></body></html>";
+
+fn source(template: Template, lang: Language) -> Source {
+    match (template, lang) {
+        (Template::Registration, Language::Zh) => Source {
+            subject: "Flow 注册链接",
+            text: "你好！\n\n请打开以下链接完成 Flow 注册，链接 30 分钟内有效：\n{base_url}/signup?vcode={code}\n\n如果不是你本人操作，请忽略这封邮件。",
+            html: "<p>你好！</p><p>请点击下面的链接完成 Flow 注册，链接 30 分钟内有效：</p>\
+<p><a href=\"{base_url}/signup?vcode={code}\">{base_url}/signup?vcode={code}</a></p>\
+<p>如果不是你本人操作，请忽略这封邮件。</p>",
+        },
+        (Template::Registration, Language::En) => Source {
+            subject: "Your Flow sign-up link",
+            text: "Hi!\n\nOpen the link below within 30 minutes to finish signing up for Flow:\n{base_url}/signup?vcode={code}\n\nIf you did not request this, please ignore this email.",
+            html: "<p>Hi!</p><p>Click the link below within 30 minutes to finish signing up for Flow:</p>\
+<p><a href=\"{base_url}/signup?vcode={code}\">{base_url}/signup?vcode={code}</a></p>\
+<p>If you did not request this, please ignore this email.</p>",
+        },
+        (Template::PasswordReset, Language::Zh) => Source {
+            subject: "Flow 重置密码",
+            text: "{username}，你好！\n\n请打开以下链接重置密码，链接 30 分钟内有效：\n{base_url}/reset?vcode={code}\n\n如果不是你本人操作，请忽略这封邮件，密码不会被修改。",
+            html: "<p>{username}，你好！</p><p>请点击下面的链接重置密码，链接 30 分钟内有效：</p>\
+<p><a href=\"{base_url}/reset?vcode={code}\">{base_url}/reset?vcode={code}</a></p>\
+<p>如果不是你本人操作，请忽略这封邮件，密码不会被修改。</p>",
+        },
+        (Template::PasswordReset, Language::En) => Source {
+            subject: "Reset your Flow password",
+            text: "Hi {username},\n\nOpen the link below within 30 minutes to reset your password:\n{base_url}/reset?vcode={code}\n\nIf you did not request this, ignore this email and your password will stay the same.",
+            html: "<p>Hi {username},</p><p>Click the link below within 30 minutes to reset your password:</p>\
+<p><a href=\"{base_url}/reset?vcode={code}\">{base_url}/reset?vcode={code}</a></p>\
+<p>If you did not request this, ignore this email and your password will stay the same.</p>",
+        },
+        (Template::NotificationDigest, Language::Zh) => Source {
+            subject: "Flow 消息汇总",
+            text: "{username}，你好！\n\n以下是你最近的消息：\n\n{summary}\n\n查看详情：{base_url}",
+            html: "<p>{username}，你好！</p><p>以下是你最近的消息：</p><p>{summary}</p>\
+<p><a href=\"{base_url}\">查看详情</a></p>",
+        },
+        (Template::NotificationDigest, Language::En) => Source {
+            subject: "Your Flow digest",
+            text: "Hi {username},\n\nHere is what happened recently:\n\n{summary}\n\nSee more at {base_url}",
+            html: "<p>Hi {username},</p><p>Here is what happened recently:</p><p>{summary}</p>\
+<p><a href=\"{base_url}\">See more</a></p>",
+        },
+    }
+}
+
+pub fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// 只扫描模板一遍，替换进去的值里即使有 {code} 之类也不会再被替换；不认识的 {...} 原样保留
+fn fill(template: &str, vars: &[(&str, String)]) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let var = after
+            .find('}')
+            .and_then(|end| vars.iter().find(|(key, _)| *key == &after[..end]).map(|(_, value)| (end, value)));
+        match var {
+            Some((end, value)) => {
+                filled.push_str(value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+pub fn render(template: Template, lang: Language, base_url: &str, vars: &[(&str, &str)]) -> RenderedEmail {
+    let source = source(template, lang);
+    let mut text_vars = vec![("base_url", base_url.trim_end_matches('/').to_string())];
+    text_vars.extend(vars.iter().map(|(k, v)| (*k, v.to_string())));
+    let html_vars = text_vars
+        .iter()
+        .map(|(k, v)| (*k, escape_html(v).replace('\n', "<br>")))
+        .collect::<Vec<(&str, String)>>();
+    RenderedEmail {
+        subject: fill(source.subject, &text_vars),
+        text: fill(source.text, &text_vars),
+        html: fill(&LAYOUT.replace("{content}", source.html), &html_vars),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::email_template::{fill, Language, render, Template};
+
+    #[test]
+    fn test_render_registration_zh() {
+        let email = render(Template::Registration, Language::Zh, "https://example.com/", &[("code", "abc")]);
+        assert_eq!(email.subject, "Flow 注册链接");
+        assert!(email.text.contains("https://example.com/signup?vcode=abc"));
+        assert!(email.html.contains("<a href=\"https://example.com/signup?vcode=abc\">"));
+    }
+
+    #[test]
+    fn test_render_escapes_html() {
+        let email = render(Template::NotificationDigest, Language::En, "https://example.com",
+                           &[("username", "<b>x</b>"), ("summary", "a\nb")]);
+        assert!(email.text.starts_with("Hi <b>x</b>,"));
+        assert!(email.html.contains("Hi &lt;b&gt;x&lt;/b&gt;,"));
+        assert!(email.html.contains("a<br>b"));
+    }
+
+    #[test]
+    fn test_fill_single_pass() {
+        let vars = [("username", "{code}".to_string()), ("code", "123".to_string())];
+        assert_eq!(fill("{username}: {code}", &vars), "{code}: 123");
+        assert_eq!(fill("{{code}} {unknown} {code", &vars), "{123} {unknown} {code");
+        let email = render(Template::NotificationDigest, Language::En, "https://example.com",
+                           &[("username", "{base_url}"), ("summary", "s")]);
+        assert!(email.text.starts_with("Hi {base_url},"));
+    }
+
+    #[test]
+    fn test_render_has_no_placeholders_left() {
+        for template in &[Template::Registration, Template::PasswordReset, Template::NotificationDigest] {
+            for lang in &[Language::Zh, Language::En] {
+                let email = render(*template, *lang, "https://example.com",
+                                   &[("code", "c"), ("username", "u"), ("summary", "s")]);
+                assert!(!email.text.contains('{') && !email.html.contains('{'), "{:?} {:?}", template, lang);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use actix_web::{HttpResponse, Responder, web};
+use async_std::future::timeout;
+use async_std::net::TcpStream;
+use mongodb::bson::doc;
+use serde::Serialize;
+
+use crate::util::config::{EmailSenderConfig, HealthConfig, TransportKind};
+use crate::util::database::Database;
+use crate::util::state::AppState;
+
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Error,
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    status: CheckStatus,
+    latency_ms: f64,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+impl Check {
+    fn skipped() -> Check {
+        Check { status: CheckStatus::Skipped, latency_ms: 0.0, error: None }
+    }
+}
+
+async fn check<F>(limit: Duration, future: F) -> Check
+    where F: Future<Output=Result<(), Box<dyn Error>>>
+{
+    let start = Instant::now();
+    let result = match timeout(limit, future).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("timed out after {}ms", limit.as_millis()).into()),
+    };
+    Check {
+        status: if result.is_ok() { CheckStatus::Ok } else { CheckStatus::Error },
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        error: result.err().map(|e| e.to_string()),
+    }
+}
+
+async fn ping_mongo(db: &Database) -> Result<(), Box<dyn Error>> {
+    db.cli.database(&db.name).run_command(doc! {"ping": 1}, None).await?;
+    Ok(())
+}
+
+// 只确认 TCP 能连上，不做 SMTP 握手和认证
+async fn connect_smtp(server: &str, port: u16) -> Result<(), Box<dyn Error>> {
+    TcpStream::connect((server, port)).await?;
+    Ok(())
+}
+
+pub async fn readiness(db: &Database, email: &EmailSenderConfig, config: &HealthConfig) -> Readiness {
+    let limit = Duration::from_millis(config.timeout_ms.unwrap_or(DEFAULT_TIMEOUT_MS));
+    let mut checks = BTreeMap::new();
+    checks.insert("mongo", check(limit, ping_mongo(db)).await);
+
+    let smtp = if config.check_smtp.unwrap_or(false) {
+        match (email.transport.unwrap_or(TransportKind::Smtp), &email.smtp_server) {
+            (TransportKind::Smtp, Some(server)) => check(limit, connect_smtp(server, email.smtp_port.unwrap_or(25))).await,
+            _ => Check::skipped(),
+        }
+    } else {
+        Check::skipped()
+    };
+    checks.insert("smtp", smtp);
+
+    Readiness {
+        ready: checks.values().all(|check| check.status != CheckStatus::Error),
+        checks,
+    }
+}
+
+async fn get_healthz_handler() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({"status": "ok"}))
+}
+
+async fn get_readyz_handler(state: web::Data<AppState>) -> impl Responder {
+    let readiness = readiness(&state.db, &state.email_config, &state.policies.health).await;
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/healthz")
+            .route(web::get().to(get_healthz_handler))
+    );
+    cfg.service(
+        web::resource("/readyz")
+            .route(web::get().to(get_readyz_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_await_test::async_test;
+
+    use crate::util::health::{check, CheckStatus};
+
+    #[async_test]
+    async fn test_check_timeout() {
+        let result = check(Duration::from_millis(10), async {
+            async_std::task::sleep(Duration::from_millis(200)).await;
+            Ok(())
+        }).await;
+        assert_eq!(result.status, CheckStatus::Error);
+        assert!(result.error.unwrap().contains("timed out"));
+        assert_eq!(check(Duration::from_millis(10), async { Ok(()) }).await.status, CheckStatus::Ok);
+    }
+}
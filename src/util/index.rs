@@ -0,0 +1,209 @@
+use std::error::Error;
+
+use mongodb::bson::{Bson, doc, Document};
+use serde::Serialize;
+
+use crate::util::database::Database;
+
+#[derive(Debug, Clone)]
+pub struct IndexSpec {
+    pub collection: &'static str,
+    pub keys: Document,
+    pub unique: bool,
+    pub expire_after: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Default, Clone)]
+pub struct IndexReport {
+    pub created: Vec<String>,
+    pub conflicting: Vec<String>,
+}
+
+impl IndexReport {
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.conflicting.is_empty()
+    }
+}
+
+fn number(v: &Bson) -> Option<f64> {
+    match v {
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        Bson::Double(d) => Some(*d),
+        _ => None,
+    }
+}
+
+impl IndexSpec {
+    pub fn new(collection: &'static str, keys: Document) -> IndexSpec {
+        IndexSpec { collection, keys, unique: false, expire_after: None }
+    }
+
+    pub fn unique(mut self) -> IndexSpec {
+        self.unique = true;
+        self
+    }
+
+    // TTL 索引，文档在该字段的时间之后若干秒被删除
+    pub fn expire_after(mut self, seconds: i64) -> IndexSpec {
+        self.expire_after = Some(seconds);
+        self
+    }
+
+    // 与 MongoDB 默认命名一致，例如 {cid: 1, comment_by: 1} -> cid_1_comment_by_1
+    pub fn name(&self) -> String {
+        self.keys
+            .iter()
+            .map(|(k, v)| format!("{}_{}", k, v))
+            .collect::<Vec<String>>()
+            .join("_")
+    }
+
+    pub(crate) fn as_doc(&self) -> Document {
+        let mut index = doc! {"key": self.keys.clone(), "name": self.name(), "unique": self.unique};
+        if let Some(seconds) = self.expire_after {
+            index.insert("expireAfterSeconds", seconds);
+        }
+        index
+    }
+
+    fn same_keys(&self, keys: &Document) -> bool {
+        self.keys.len() == keys.len() && self.keys
+            .iter()
+            .zip(keys.iter())
+            .all(|((k1, v1), (k2, v2))| k1 == k2 && number(v1) == number(v2))
+    }
+
+    fn describe(&self) -> String {
+        format!("{}.{}", self.collection, self.name())
+    }
+}
+
+pub async fn create_indexes(db: &Database, indexes: &[IndexSpec]) -> Result<(), Box<dyn Error>> {
+    for index in indexes {
+        db.cli
+            .database(&db.name)
+            .run_command(doc! {"createIndexes": index.collection, "indexes": [index.as_doc()]}, None)
+            .await?;
+    }
+    Ok(())
+}
+
+// 已有同键索引且唯一性不弱于要求、TTL 一致时视为满足
+fn satisfies(existing: &Document, index: &IndexSpec) -> bool {
+    existing.get_document("key").map_or(false, |keys| index.same_keys(keys))
+        && (existing.get_bool("unique").unwrap_or(false) || !index.unique)
+        && existing.get("expireAfterSeconds").and_then(number) == index.expire_after.map(|s| s as f64)
+}
+
+// 迁移用：已满足的索引跳过，其余创建，失败直接返回错误
+pub async fn create_missing_indexes(db: &Database, indexes: &[IndexSpec]) -> Result<(), Box<dyn Error>> {
+    for index in indexes {
+        if !list_indexes(db, index.collection).await?.iter().any(|e| satisfies(e, index)) {
+            create_indexes(db, &[index.clone()]).await?;
+        }
+    }
+    Ok(())
+}
+
+// 删除与唯一索引同键但不唯一的旧索引，之后才能以同名创建唯一索引
+pub async fn drop_non_unique_indexes(db: &Database, indexes: &[IndexSpec]) -> Result<(), Box<dyn Error>> {
+    for index in indexes.iter().filter(|index| index.unique) {
+        for existing in list_indexes(db, index.collection).await? {
+            let same_keys = existing.get_document("key").map_or(false, |keys| index.same_keys(keys));
+            if same_keys && !existing.get_bool("unique").unwrap_or(false) {
+                let name = existing.get_str("name")?;
+                db.cli
+                    .database(&db.name)
+                    .run_command(doc! {"dropIndexes": index.collection, "index": name}, None)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn list_indexes(db: &Database, collection: &str) -> Result<Vec<Document>, Box<dyn Error>> {
+    let result = db.cli
+        .database(&db.name)
+        .run_command(doc! {"listIndexes": collection}, None)
+        .await;
+    // 集合不存在时 MongoDB 返回 NamespaceNotFound，相当于没有索引
+    let result = match result {
+        Ok(result) => result,
+        Err(_) => return Ok(vec![]),
+    };
+    Ok(result
+        .get_document("cursor")?
+        .get_array("firstBatch")?
+        .iter()
+        .filter_map(|index| index.as_document().cloned())
+        .collect())
+}
+
+// 缺少的索引直接创建；同名或同键但唯一性、TTL 不一致的索引只报告，不自动删除
+pub async fn ensure_indexes(db: &Database, indexes: &[IndexSpec]) -> Result<IndexReport, Box<dyn Error>> {
+    let mut report = IndexReport::default();
+    for index in indexes {
+        let existing = list_indexes(db, index.collection).await?;
+        let matched = existing.iter().find(|e| {
+            e.get_document("key").map_or(false, |keys| index.same_keys(keys))
+                || e.get_str("name").map_or(false, |name| name == index.name())
+        });
+        match matched {
+            Some(e) => {
+                let unique = e.get_bool("unique").unwrap_or(false);
+                let same_keys = e.get_document("key").map_or(false, |keys| index.same_keys(keys));
+                let expire_after = e.get("expireAfterSeconds").and_then(number);
+                if !same_keys || unique != index.unique || expire_after != index.expire_after.map(|s| s as f64) {
+                    report.conflicting.push(format!("{} differs from existing index {}", index.describe(), e.get_str("name").unwrap_or("?")));
+                }
+            }
+            None => match create_indexes(db, &[index.clone()]).await {
+                Ok(_) => report.created.push(index.describe()),
+                Err(err) => report.conflicting.push(format!("{} cannot be created: {}", index.describe(), err)),
+            },
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use mongodb::bson::doc;
+
+    use crate::util::index::{IndexSpec, satisfies};
+
+    #[test]
+    fn test_index_name() {
+        assert_eq!(IndexSpec::new("Comment", doc! {"cid": 1, "comment_by": 1}).name(), "cid_1_comment_by_1");
+    }
+
+    #[test]
+    fn test_same_keys_ignores_number_type() {
+        let index = IndexSpec::new("User", doc! {"username": 1});
+        assert!(index.same_keys(&doc! {"username": 1.0}));
+        assert!(!index.same_keys(&doc! {"username": -1}));
+        assert!(!index.same_keys(&doc! {"email": 1}));
+    }
+
+    #[test]
+    fn test_satisfies() {
+        let unique = IndexSpec::new("User", doc! {"username": 1}).unique();
+        let plain = IndexSpec::new("User", doc! {"username": 1});
+        let existing_unique = doc! {"key": {"username": 1}, "name": "username_1", "unique": true};
+        let existing_plain = doc! {"key": {"username": 1}, "name": "username_1"};
+        assert!(satisfies(&existing_unique, &unique));
+        assert!(satisfies(&existing_unique, &plain));
+        assert!(satisfies(&existing_plain, &plain));
+        assert!(!satisfies(&existing_plain, &unique));
+        assert!(!satisfies(&existing_plain, &plain.clone().expire_after(60)));
+    }
+
+    #[test]
+    fn test_expire_after() {
+        let index = IndexSpec::new("EmailCode", doc! {"created_at": 1}).expire_after(1800);
+        assert_eq!(index.as_doc().get_i64("expireAfterSeconds").unwrap(), 1800);
+        assert!(IndexSpec::new("User", doc! {"username": 1}).as_doc().get("expireAfterSeconds").is_none());
+    }
+}
@@ -0,0 +1,85 @@
+use std::env;
+use std::error::Error;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::Utc;
+use env_logger::Builder;
+use log::Record;
+use serde_json::{Map, Value};
+
+use crate::util::config::{LogConfig, LogFormat, optional};
+
+pub const LOG_CONFIG: &str = "config/Log.toml";
+const DEFAULT_LEVEL: &str = "info";
+
+// 由 init 设置，访问日志据此决定输出 JSON 还是文本
+static JSON_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn json_enabled() -> bool {
+    JSON_ENABLED.load(Ordering::Relaxed)
+}
+
+// 文件不存在时用默认配置，格式不对则启动失败
+pub async fn load() -> Result<LogConfig, Box<dyn Error>> {
+    optional(LOG_CONFIG).await
+}
+
+// 消息本身是 JSON 对象时（例如访问日志）直接展开为字段，否则放在 msg 中；
+// ts / level / target 最后写入，消息里的同名字段不能覆盖它们
+pub fn json_line(record: &Record) -> String {
+    let mut line = Map::new();
+    let message = record.args().to_string();
+    match serde_json::from_str::<Value>(&message) {
+        Ok(Value::Object(fields)) => line.extend(fields),
+        _ => {
+            line.insert("msg".to_string(), Value::String(message));
+        }
+    }
+    line.insert("ts".to_string(), Value::String(Utc::now().to_rfc3339()));
+    line.insert("level".to_string(), Value::String(record.level().to_string()));
+    line.insert("target".to_string(), Value::String(record.target().to_string()));
+    Value::Object(line).to_string()
+}
+
+pub fn init(config: &LogConfig) {
+    JSON_ENABLED.store(config.format == Some(LogFormat::Json), Ordering::Relaxed);
+    let mut builder = Builder::new();
+    let level = env::var("RUST_LOG").ok().or_else(|| config.level.clone());
+    builder.parse_filters(level.as_deref().unwrap_or(DEFAULT_LEVEL));
+    if config.format == Some(LogFormat::Json) {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+    // 测试中可能重复初始化，忽略错误
+    let _ = builder.try_init();
+}
+
+#[cfg(test)]
+mod test {
+    use log::{Level, Record};
+    use serde_json::Value;
+
+    use crate::util::logger::json_line;
+
+    #[test]
+    fn test_json_line() {
+        let line = json_line(&Record::builder()
+            .args(format_args!("{}", "{\"status\":200}"))
+            .level(Level::Info)
+            .target("access")
+            .build());
+        let value = serde_json::from_str::<Value>(&line).unwrap();
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["target"], "access");
+        let line = json_line(&Record::builder()
+            .args(format_args!("{}", "{\"level\":\"ERROR\",\"target\":\"forged\"}"))
+            .level(Level::Info)
+            .target("access")
+            .build());
+        let value = serde_json::from_str::<Value>(&line).unwrap();
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], "access");
+        let line = json_line(&Record::builder().args(format_args!("plain")).level(Level::Warn).build());
+        assert_eq!(serde_json::from_str::<Value>(&line).unwrap()["msg"], "plain");
+    }
+}
@@ -21,6 +21,7 @@ macro_rules! json_response {
                 },
                 Err(e) => {
                     use crate::util::json_response::JsonResponse;
+                    log::warn!(target: "response", "{}", e);
                     return web::Json(JsonResponse{data: None, error: Some(e.to_string()), meta:None});
                 },
             }
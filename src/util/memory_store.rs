@@ -0,0 +1,358 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use mongodb::bson::{Bson, doc, Document, from_bson};
+use mongodb::bson::oid::ObjectId;
+use serde::de::DeserializeOwned;
+
+use crate::resources::comment::Comment;
+use crate::resources::course::Course;
+use crate::resources::detail::Detail;
+use crate::resources::rate::Rate;
+use crate::resources::user::User;
+use crate::util::repository::{CommentRepository, CourseRepository, DetailRepository, RateRepository, UserRepository};
+
+// 内存中的集合，只支持处理器用到的查询和更新操作符，用于不连 MongoDB 的测试
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    collections: Mutex<HashMap<String, Vec<Document>>>,
+}
+
+fn number(v: &Bson) -> Option<f64> {
+    match v {
+        Bson::Int32(i) => Some(*i as f64),
+        Bson::Int64(i) => Some(*i as f64),
+        Bson::Double(f) => Some(*f),
+        _ => None,
+    }
+}
+
+fn equals(a: &Bson, b: &Bson) -> bool {
+    match (number(a), number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn compare(a: &Bson, b: &Bson) -> Option<Ordering> {
+    match (number(a), number(b), a, b) {
+        (Some(a), Some(b), _, _) => a.partial_cmp(&b),
+        (_, _, Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn lookup<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut parts = path.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        value = value.as_document()?.get(part)?;
+    }
+    Some(value)
+}
+
+// 数组字段和 MongoDB 一样，任一元素满足即可
+fn match_element(value: Option<&Bson>, pred: impl Fn(Option<&Bson>) -> bool) -> bool {
+    pred(value) || matches!(value, Some(Bson::Array(items)) if items.iter().any(|item| pred(Some(item))))
+}
+
+// $ne / $nin 是 $eq / $in 的否定，要求数组中没有任何元素满足，不能逐个元素判断
+fn match_value(value: Option<&Bson>, cond: &Bson) -> bool {
+    match cond {
+        Bson::Document(ops) if ops.keys().next().map_or(false, |k| k.starts_with('$')) => {
+            ops.iter().all(|(op, arg)| match op.as_str() {
+                "$ne" => !match_value(value, arg),
+                "$nin" => !arg.as_array().map_or(false, |a| a.iter().any(|x| match_value(value, x))),
+                "$exists" => value.is_some() == arg.as_bool().unwrap_or(true),
+                op => match_element(value, |v| match_operator(v, op, arg)),
+            })
+        }
+        Bson::Array(_) => value.map_or(false, |v| equals(v, cond)),
+        Bson::Null => match_element(value, |v| v.map_or(true, |v| *v == Bson::Null)),
+        _ => match_element(value, |v| v.map_or(false, |v| equals(v, cond))),
+    }
+}
+
+fn match_operator(value: Option<&Bson>, op: &str, arg: &Bson) -> bool {
+    match (op, value) {
+        ("$eq", _) => match_value(value, arg),
+        ("$in", _) => arg.as_array().map_or(false, |a| a.iter().any(|x| match_value(value, x))),
+        ("$gt", Some(v)) => compare(v, arg) == Some(Ordering::Greater),
+        ("$gte", Some(v)) => compare(v, arg).map_or(false, |o| o != Ordering::Less),
+        ("$lt", Some(v)) => compare(v, arg) == Some(Ordering::Less),
+        ("$lte", Some(v)) => compare(v, arg).map_or(false, |o| o != Ordering::Greater),
+        _ => false,
+    }
+}
+
+pub fn matches(doc: &Document, filter: &Document) -> bool {
+    filter.iter().all(|(key, cond)| match key.as_str() {
+        "$or" => cond.as_array().map_or(false, |a| a.iter().any(|f| f.as_document().map_or(false, |f| matches(doc, f)))),
+        "$and" => cond.as_array().map_or(false, |a| a.iter().all(|f| f.as_document().map_or(false, |f| matches(doc, f)))),
+        "$nor" => cond.as_array().map_or(false, |a| !a.iter().any(|f| f.as_document().map_or(false, |f| matches(doc, f)))),
+        _ => match_value(lookup(doc, key), cond),
+    })
+}
+
+pub fn apply(doc: &mut Document, update: &Document) -> Result<(), Box<dyn Error>> {
+    for (op, fields) in update {
+        let fields = fields.as_document().ok_or_else(|| format!("{} expects a document", op))?;
+        for (field, value) in fields {
+            match op.as_str() {
+                "$set" => {
+                    doc.insert(field.clone(), value.clone());
+                }
+                "$unset" => {
+                    doc.remove(field);
+                }
+                "$inc" => {
+                    let current = doc.get(field).map_or(Some(0.0), number).ok_or_else(|| format!("{} is not a number", field))?;
+                    let inc = number(value).ok_or("$inc expects a number")?;
+                    let result = match (doc.get(field), value) {
+                        (Some(Bson::Double(_)), _) | (_, Bson::Double(_)) => Bson::Double(current + inc),
+                        (Some(Bson::Int32(_)), Bson::Int32(_)) | (None, Bson::Int32(_)) => Bson::Int32((current + inc) as i32),
+                        _ => Bson::Int64((current + inc) as i64),
+                    };
+                    doc.insert(field.clone(), result);
+                }
+                "$addToSet" | "$push" => {
+                    let mut items = match doc.get(field) {
+                        Some(Bson::Array(items)) => items.clone(),
+                        None => vec![],
+                        _ => return Err(format!("{} is not an array", field).into()),
+                    };
+                    if op == "$push" || !items.iter().any(|item| equals(item, value)) {
+                        items.push(value.clone());
+                    }
+                    doc.insert(field.clone(), items);
+                }
+                "$pull" => {
+                    if let Some(Bson::Array(items)) = doc.get(field) {
+                        let items = items.iter().filter(|item| !match_value(Some(item), value)).cloned().collect::<Vec<Bson>>();
+                        doc.insert(field.clone(), items);
+                    }
+                }
+                _ => return Err(format!("unsupported update operator {}", op).into()),
+            }
+        }
+    }
+    Ok(())
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, HashMap<String, Vec<Document>>>, Box<dyn Error>> {
+        Ok(self.collections.lock().map_err(|e| e.to_string())?)
+    }
+
+    pub fn insert(&self, collection: &str, mut doc: Document) -> Result<Bson, Box<dyn Error>> {
+        if !doc.contains_key("_id") {
+            doc.insert("_id", ObjectId::new());
+        }
+        let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        self.lock()?.entry(collection.to_string()).or_default().push(doc);
+        Ok(id)
+    }
+
+    pub fn find(&self, collection: &str, filter: &Document) -> Result<Vec<Document>, Box<dyn Error>> {
+        Ok(self
+            .lock()?
+            .get(collection)
+            .map(|docs| docs.iter().filter(|doc| matches(doc, filter)).cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn find_as<T: DeserializeOwned>(&self, collection: &str, filter: &Document) -> Result<Vec<T>, Box<dyn Error>> {
+        Ok(self
+            .find(collection, filter)?
+            .into_iter()
+            .filter_map(|doc| from_bson::<T>(Bson::Document(doc)).ok())
+            .collect())
+    }
+
+    pub fn update(&self, collection: &str, filter: &Document, update: &Document, many: bool) -> Result<i64, Box<dyn Error>> {
+        let mut collections = self.lock()?;
+        let mut modified = 0;
+        for doc in collections.entry(collection.to_string()).or_default().iter_mut() {
+            if matches(doc, filter) {
+                let before = doc.clone();
+                apply(doc, update)?;
+                if *doc != before {
+                    modified += 1;
+                }
+                if !many {
+                    break;
+                }
+            }
+        }
+        Ok(modified)
+    }
+
+    // 和 upsert 的 replace_one 一致，返回被替换或新插入文档的 _id
+    pub fn replace(&self, collection: &str, filter: &Document, mut replacement: Document) -> Result<Bson, Box<dyn Error>> {
+        {
+            let mut collections = self.lock()?;
+            let docs = collections.entry(collection.to_string()).or_default();
+            if let Some(doc) = docs.iter_mut().find(|doc| matches(doc, filter)) {
+                let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+                replacement.insert("_id", id.clone());
+                *doc = replacement;
+                return Ok(id);
+            }
+        }
+        self.insert(collection, replacement)
+    }
+
+    pub fn delete(&self, collection: &str, filter: &Document, many: bool) -> Result<i64, Box<dyn Error>> {
+        let mut collections = self.lock()?;
+        let docs = collections.entry(collection.to_string()).or_default();
+        let mut deleted = 0;
+        docs.retain(|doc| {
+            if (many || deleted == 0) && matches(doc, filter) {
+                deleted += 1;
+                false
+            } else {
+                true
+            }
+        });
+        Ok(deleted)
+    }
+}
+
+#[async_trait(?Send)]
+impl UserRepository for MemoryStore {
+    async fn find_user(&self, filter: Document) -> Result<Option<User>, Box<dyn Error>> {
+        Ok(self.find_as::<User>("User", &filter)?.into_iter().next())
+    }
+
+    async fn count_users(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.find("User", &filter)?.len() as i64)
+    }
+
+    async fn update_user(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>> {
+        self.update("User", &filter, &update, false)
+    }
+
+    async fn delete_user(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        self.delete("User", &filter, false)
+    }
+}
+
+#[async_trait(?Send)]
+impl CommentRepository for MemoryStore {
+    async fn find_comments(&self, filter: Document) -> Result<Vec<Comment>, Box<dyn Error>> {
+        self.find_as("Comment", &filter)
+    }
+
+    async fn replace_comment(&self, filter: Document, comment: Document) -> Result<Bson, Box<dyn Error>> {
+        self.replace("Comment", &filter, comment)
+    }
+
+    async fn update_comment(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>> {
+        self.update("Comment", &filter, &update, false)
+    }
+
+    async fn delete_comment(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        self.delete("Comment", &filter, false)
+    }
+
+    async fn update_comments(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>> {
+        self.update("Comment", &filter, &update, true)
+    }
+
+    async fn delete_comments(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        self.delete("Comment", &filter, true)
+    }
+}
+
+#[async_trait(?Send)]
+impl CourseRepository for MemoryStore {
+    // 和 Mongo 实现的 $group 一致：name / faculty 取第一条，taught_by 去重合并
+    async fn find_courses(&self, filter: Document) -> Result<Vec<Course>, Box<dyn Error>> {
+        let mut grouped: Vec<Document> = vec![];
+        for section in self.find("Course", &filter)? {
+            let cid = section.get("cid").cloned().unwrap_or(Bson::Null);
+            let taught_by = section.get("taught_by").cloned().unwrap_or(Bson::Null);
+            match grouped.iter_mut().find(|course| course.get("cid") == Some(&cid)) {
+                Some(course) => apply(course, &doc! {"$addToSet": {"taught_by": taught_by}})?,
+                None => grouped.push(doc! {
+                    "cid": cid,
+                    "name": section.get("name").cloned().unwrap_or(Bson::Null),
+                    "faculty": section.get("faculty").cloned().unwrap_or(Bson::Null),
+                    "taught_by": [taught_by],
+                }),
+            }
+        }
+        Ok(grouped
+            .into_iter()
+            .filter_map(|course| from_bson::<Course>(Bson::Document(course)).ok())
+            .collect())
+    }
+}
+
+#[async_trait(?Send)]
+impl DetailRepository for MemoryStore {
+    async fn find_details(&self, filter: Document) -> Result<Vec<Detail>, Box<dyn Error>> {
+        self.find_as("Detail", &filter)
+    }
+}
+
+#[async_trait(?Send)]
+impl RateRepository for MemoryStore {
+    async fn find_rates(&self, filter: Document) -> Result<Vec<Rate>, Box<dyn Error>> {
+        self.find_as("Rate", &filter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mongodb::bson::doc;
+
+    use crate::util::memory_store::{apply, matches, MemoryStore};
+
+    #[test]
+    fn test_matches() {
+        let doc = doc! {"cid": "CS101", "taught_by": ["Alice", "Bob"], "rate": {"likes": 4}, "year": 2020};
+        assert!(matches(&doc, &doc! {"cid": "CS101", "taught_by": "Bob"}));
+        assert!(matches(&doc, &doc! {"$or": [{"cid": "CS102"}, {"rate.likes": 4}]}));
+        assert!(matches(&doc, &doc! {"cid": {"$in": ["CS101", "CS102"]}, "year": {"$gte": 2020_i64}}));
+        assert!(!matches(&doc, &doc! {"cid": "CS101", "year": {"$lt": 2020}}));
+        assert!(matches(&doc, &doc! {"missing": null}));
+        assert!(matches(&doc, &doc! {"taught_by": ["Alice", "Bob"]}));
+        assert!(!matches(&doc, &doc! {"taught_by": ["Bob"]}));
+        assert!(matches(&doc, &doc! {"taught_by": {"$in": ["Bob", "Carol"]}}));
+        assert!(matches(&doc, &doc! {"taught_by": {"$gt": "B"}}));
+    }
+
+    #[test]
+    fn test_matches_negative_operators_on_arrays() {
+        let doc = doc! {"taught_by": ["Alice", "Bob"], "tags": []};
+        assert!(!matches(&doc, &doc! {"taught_by": {"$ne": "Bob"}}));
+        assert!(matches(&doc, &doc! {"taught_by": {"$ne": "Carol"}}));
+        assert!(!matches(&doc, &doc! {"taught_by": {"$nin": ["Bob", "Carol"]}}));
+        assert!(matches(&doc, &doc! {"taught_by": {"$nin": ["Carol"]}}));
+        assert!(matches(&doc, &doc! {"tags": {"$ne": "hard"}}));
+        assert!(!matches(&doc, &doc! {"taught_by": {"$ne": "Bob", "$gte": "Alice"}}));
+        assert!(matches(&doc, &doc! {"taught_by": {"$ne": "Carol", "$gte": "Bob"}}));
+    }
+
+    #[test]
+    fn test_update_and_delete() {
+        let store = MemoryStore::new();
+        store.insert("Comment", doc! {"cid": "CS101", "helpful": 1, "tags": []}).unwrap();
+        store.insert("Comment", doc! {"cid": "CS102", "helpful": 0, "tags": []}).unwrap();
+        let update = doc! {"$inc": {"helpful": 1}, "$addToSet": {"tags": "hard"}};
+        assert_eq!(store.update("Comment", &doc! {"cid": "CS101"}, &update, false).unwrap(), 1);
+        let found = store.find("Comment", &doc! {"helpful": 2, "tags": "hard"}).unwrap();
+        assert_eq!(found.len(), 1);
+        assert!(apply(&mut found[0].clone(), &doc! {"$rename": {"a": "b"}}).is_err());
+        assert_eq!(store.delete("Comment", &doc! {}, true).unwrap(), 2);
+        assert!(store.find("Comment", &doc! {}).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::{HttpResponse, Responder, web};
+use lazy_static::lazy_static;
+use mongodb::bson::doc;
+
+use crate::resources::session::SESSION_POOL;
+use crate::util::database::Database;
+use crate::util::state::AppState;
+
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const QUEUE_STATUS: [&str; 4] = ["pending", "sending", "sent", "dead"];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; 11],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            if seconds <= *bound {
+                self.buckets[i] += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn write(&self, out: &mut String, name: &str, labels: &str) {
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, self.buckets[i]);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    requests: BTreeMap<(String, String, u16), u64>,
+    request_latency: BTreeMap<(String, String), Histogram>,
+    operation_latency: BTreeMap<String, Histogram>,
+    operation_errors: BTreeMap<String, u64>,
+    rate_limited: u64,
+    email_sent: u64,
+    email_failed: u64,
+}
+
+lazy_static! {
+    pub static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+pub fn observe_request(method: &str, route: &str, status: u16, seconds: f64) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        *metrics.requests.entry((method.to_string(), route.to_string(), status)).or_insert(0) += 1;
+        metrics.request_latency.entry((method.to_string(), route.to_string())).or_default().observe(seconds);
+    }
+}
+
+pub fn inc_rate_limited() {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.rate_limited += 1;
+    }
+}
+
+pub fn inc_email(sent: bool) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        if sent {
+            metrics.email_sent += 1;
+        } else {
+            metrics.email_failed += 1;
+        }
+    }
+}
+
+// 记录资源函数整体的耗时和失败次数，除 MongoDB 外也包含密码哈希、发邮件等开销
+pub async fn timed<T, E, F>(operation: &str, future: F) -> Result<T, E>
+    where F: Future<Output=Result<T, E>>
+{
+    let start = Instant::now();
+    let result = future.await;
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.operation_latency.entry(operation.to_string()).or_default().observe(start.elapsed().as_secs_f64());
+        if result.is_err() {
+            *metrics.operation_errors.entry(operation.to_string()).or_insert(0) += 1;
+        }
+    }
+    result
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// gauges 为渲染时采集的 (名称, 标签, 值)
+pub fn render(gauges: &[(&str, String, f64)]) -> String {
+    let mut out = String::new();
+    let metrics = match METRICS.lock() {
+        Ok(metrics) => metrics,
+        Err(_) => return out,
+    };
+    out.push_str("# HELP http_requests_total Total HTTP requests by route and status.\n# TYPE http_requests_total counter\n");
+    for ((method, route, status), count) in &metrics.requests {
+        let _ = writeln!(out, "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, escape(route), status, count);
+    }
+    out.push_str("# HELP http_request_duration_seconds HTTP request latency.\n# TYPE http_request_duration_seconds histogram\n");
+    for ((method, route), histogram) in &metrics.request_latency {
+        histogram.write(&mut out, "http_request_duration_seconds", &format!("method=\"{}\",route=\"{}\"", method, escape(route)));
+    }
+    out.push_str("# HELP resource_operation_duration_seconds Resource function latency.\n# TYPE resource_operation_duration_seconds histogram\n");
+    for (operation, histogram) in &metrics.operation_latency {
+        histogram.write(&mut out, "resource_operation_duration_seconds", &format!("operation=\"{}\"", operation));
+    }
+    out.push_str("# HELP resource_operation_errors_total Failed resource function calls.\n# TYPE resource_operation_errors_total counter\n");
+    for (operation, count) in &metrics.operation_errors {
+        let _ = writeln!(out, "resource_operation_errors_total{{operation=\"{}\"}} {}", operation, count);
+    }
+    out.push_str("# HELP rate_limit_rejections_total Requests rejected by the per-user API limit.\n# TYPE rate_limit_rejections_total counter\n");
+    let _ = writeln!(out, "rate_limit_rejections_total {}", metrics.rate_limited);
+    out.push_str("# HELP email_send_total Email send attempts by result.\n# TYPE email_send_total counter\n");
+    let _ = writeln!(out, "email_send_total{{result=\"sent\"}} {}", metrics.email_sent);
+    let _ = writeln!(out, "email_send_total{{result=\"failed\"}} {}", metrics.email_failed);
+    let mut last = "";
+    for (name, labels, value) in gauges {
+        if *name != last {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            last = name;
+        }
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+    out
+}
+
+async fn gauges(db: &Database) -> Vec<(&'static str, String, f64)> {
+    let mut gauges = vec![];
+    if let Ok(pool) = SESSION_POOL.lock() {
+        gauges.push(("sessions_active", String::new(), pool.len() as f64));
+    }
+    let queue = db.cli.database(&db.name).collection("EmailQueue");
+    for status in &QUEUE_STATUS {
+        if let Ok(count) = queue.count_documents(doc! {"status": *status}, None).await {
+            gauges.push(("email_queue_depth", format!("status=\"{}\"", status), count as f64));
+        }
+    }
+    gauges
+}
+
+async fn get_metrics_handler(state: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&gauges(&state.db).await))
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/metrics")
+            .route(web::get().to(get_metrics_handler))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use futures_await_test::async_test;
+
+    use crate::util::metrics::{observe_request, render, timed};
+
+    #[async_test]
+    async fn test_render() {
+        observe_request("GET", "/course/{cid}", 200, 0.02);
+        let _ = timed("get_course", async { Err::<(), &str>("failed") }).await;
+        let text = render(&[("sessions_active", String::new(), 3.0)]);
+        assert!(text.contains("http_requests_total{method=\"GET\",route=\"/course/{cid}\",status=\"200\"}"));
+        assert!(text.contains("http_request_duration_seconds_bucket{method=\"GET\",route=\"/course/{cid}\",le=\"0.025\"}"));
+        assert!(text.contains("resource_operation_errors_total{operation=\"get_course\"}"));
+        assert!(text.contains("sessions_active 3"));
+    }
+}
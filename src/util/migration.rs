@@ -0,0 +1,279 @@
+use std::error::Error;
+use std::io;
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::future::LocalBoxFuture;
+use futures::stream::StreamExt;
+use mongodb::bson::{Bson, doc, from_bson, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+
+use crate::util::database::Database;
+use crate::util::index::{create_missing_indexes, drop_non_unique_indexes, IndexSpec};
+
+type MigrationFn = for<'a> fn(&'a Database) -> LocalBoxFuture<'a, Result<(), Box<dyn Error>>>;
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub up: MigrationFn,
+    pub indexes: Vec<IndexSpec>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    version: u32,
+    name: String,
+    applied_at: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    version: u32,
+    name: &'static str,
+    applied_at: Option<String>,
+}
+
+fn fill_learnt_course(db: &Database) -> LocalBoxFuture<Result<(), Box<dyn Error>>> {
+    Box::pin(async move {
+        db.cli
+            .database(&db.name)
+            .collection("User")
+            .update_many(doc! {"learnt_course": {"$exists": false}}, doc! {"$set": {"learnt_course": []}}, None)
+            .await?;
+        Ok(())
+    })
+}
+
+fn fill_comment_votes(db: &Database) -> LocalBoxFuture<Result<(), Box<dyn Error>>> {
+    Box::pin(async move {
+        let comment = db.cli.database(&db.name).collection("Comment");
+        for field in &["helpful", "not_helpful"] {
+            comment
+                .update_many(doc! {*field: {"$exists": false}}, doc! {"$set": {*field: 0}}, None)
+                .await?;
+        }
+        Ok(())
+    })
+}
+
+// 旧版本把 bcrypt 哈希 base64 编码后存入 permanent_token，统一为原始哈希
+fn decode_legacy_token(db: &Database) -> LocalBoxFuture<Result<(), Box<dyn Error>>> {
+    Box::pin(async move {
+        let user = db.cli.database(&db.name).collection("User");
+        let mut cursor = user
+            .find(doc! {"permanent_token": {"$not": {"$regex": "^\\$2"}}}, None)
+            .await?;
+        while let Some(document) = cursor.next().await {
+            let document = document?;
+            let token = document.get_str("permanent_token").unwrap_or("").replace("\n", "");
+            let hash = String::from_utf8(base64::decode(&token).unwrap_or_default()).unwrap_or_default();
+            if hash.starts_with("$2") {
+                user.update_one(
+                    doc! {"_id": document.get("_id").cloned().unwrap_or(Bson::Null)},
+                    doc! {"$set": {"permanent_token": hash}},
+                    None,
+                ).await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// ObjectId 前 4 字节是大端序的秒级时间戳
+fn object_id_time(id: &ObjectId) -> DateTime<Utc> {
+    let bytes = id.bytes();
+    Utc.timestamp(i64::from(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])), 0)
+}
+
+fn fill_comment_timestamps(db: &Database) -> LocalBoxFuture<Result<(), Box<dyn Error>>> {
+    Box::pin(async move {
+        let comment = db.cli.database(&db.name).collection("Comment");
+        let mut cursor = comment.find(doc! {"created_at": {"$exists": false}}, None).await?;
+        while let Some(document) = cursor.next().await {
+            let document = document?;
+            if let Ok(id) = document.get_object_id("_id") {
+                let created_at = object_id_time(id);
+                comment.update_one(
+                    doc! {"_id": id.clone()},
+                    doc! {"$set": {"created_at": created_at, "updated_at": created_at}},
+                    None,
+                ).await?;
+            }
+        }
+        Ok(())
+    })
+}
+
+// 旧版本发出的邮件正文（含验证码）一直留在队列里，清掉并补上 finished_at 交给 TTL 索引删除
+fn scrub_sent_emails(db: &Database) -> LocalBoxFuture<Result<(), Box<dyn Error>>> {
+    Box::pin(async move {
+        let queue = db.cli.database(&db.name).collection("EmailQueue");
+        queue
+            .update_many(doc! {"status": "sent"}, doc! {"$set": {"email.body": ""}, "$unset": {"email.html": ""}}, None)
+            .await?;
+        queue
+            .update_many(
+                doc! {"status": {"$in": ["sent", "dead"]}, "finished_at": {"$exists": false}},
+                doc! {"$set": {"finished_at": Utc::now()}},
+                None,
+            )
+            .await?;
+        Ok(())
+    })
+}
+
+fn no_op(_: &Database) -> LocalBoxFuture<Result<(), Box<dyn Error>>> {
+    Box::pin(async { Ok(()) })
+}
+
+// 版本 5 的索引清单，固定在这里；以后修改各资源的 indexes() 需要新增迁移，不能改这里
+fn declared_indexes() -> Vec<IndexSpec> {
+    vec![
+        IndexSpec::new("User", doc! {"username": 1}).unique(),
+        IndexSpec::new("User", doc! {"email": 1}).unique(),
+        IndexSpec::new("Comment", doc! {"cid": 1, "comment_by": 1}).unique(),
+        IndexSpec::new("Comment", doc! {"comment_by": 1}),
+        IndexSpec::new("Course", doc! {"faculty": 1}),
+        IndexSpec::new("Detail", doc! {"cid": 1}).unique(),
+        IndexSpec::new("EmailQueue", doc! {"id": 1}).unique(),
+        IndexSpec::new("EmailQueue", doc! {"status": 1, "next_attempt_at": 1}),
+        IndexSpec::new("EmailCode", doc! {"email": 1}).unique(),
+        IndexSpec::new("EmailCode", doc! {"created_at": 1}).expire_after(1800),
+        IndexSpec::new("Invite", doc! {"code": 1}).unique(),
+    ]
+}
+
+// 版本 4 建的 username / email / Detail.cid 索引不唯一，先删掉才能建同名唯一索引
+fn drop_lookup_indexes(db: &Database) -> LocalBoxFuture<Result<(), Box<dyn Error>>> {
+    Box::pin(async move { drop_non_unique_indexes(db, &declared_indexes()).await })
+}
+
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration { version: 1, name: "fill_learnt_course", up: fill_learnt_course, indexes: vec![] },
+        Migration { version: 2, name: "fill_comment_votes", up: fill_comment_votes, indexes: vec![] },
+        Migration { version: 3, name: "decode_legacy_token", up: decode_legacy_token, indexes: vec![] },
+        Migration {
+            version: 4,
+            name: "lookup_indexes",
+            up: no_op,
+            indexes: vec![
+                IndexSpec::new("User", doc! {"username": 1}),
+                IndexSpec::new("User", doc! {"email": 1}),
+                IndexSpec::new("Comment", doc! {"cid": 1}),
+                IndexSpec::new("Course", doc! {"cid": 1}),
+                IndexSpec::new("Detail", doc! {"cid": 1}),
+                IndexSpec::new("Rate", doc! {"cid": 1}),
+            ],
+        },
+        Migration { version: 5, name: "declared_indexes", up: drop_lookup_indexes, indexes: declared_indexes() },
+        Migration { version: 6, name: "fill_comment_timestamps", up: fill_comment_timestamps, indexes: vec![] },
+        Migration {
+            version: 7,
+            name: "scrub_sent_emails",
+            up: scrub_sent_emails,
+            indexes: vec![IndexSpec::new("EmailQueue", doc! {"finished_at": 1}).expire_after(7 * 24 * 60 * 60)],
+        },
+    ]
+}
+
+pub async fn applied(db: &Database) -> Result<Vec<AppliedMigration>, Box<dyn Error>> {
+    let mut cursor = db.cli.database(&db.name).collection("Migration").find(doc! {}, None).await?;
+    let mut applied = vec![];
+    while let Some(document) = cursor.next().await {
+        applied.push(from_bson::<AppliedMigration>(Bson::Document(document?))?);
+    }
+    applied.sort_by_key(|m| m.version);
+    Ok(applied)
+}
+
+// 已执行的最新迁移版本，一个都没执行时为 0
+pub async fn current_version(db: &Database) -> Result<u32, Box<dyn Error>> {
+    Ok(applied(db).await?.last().map_or(0, |m| m.version))
+}
+
+pub async fn status(db: &Database) -> Result<Vec<MigrationStatus>, Box<dyn Error>> {
+    let applied = applied(db).await?;
+    Ok(migrations()
+        .into_iter()
+        .map(|m| MigrationStatus {
+            version: m.version,
+            name: m.name,
+            applied_at: applied.iter().find(|a| a.version == m.version).map(|a| a.applied_at.clone()),
+        })
+        .collect())
+}
+
+pub async fn up(db: &Database, target: Option<u32>) -> Result<Vec<MigrationStatus>, Box<dyn Error>> {
+    let applied = applied(db).await?;
+    let mut done = vec![];
+    for migration in migrations() {
+        if applied.iter().any(|a| a.version == migration.version) {
+            continue;
+        }
+        if target.map_or(false, |target| migration.version > target) {
+            break;
+        }
+        (migration.up)(db).await?;
+        create_missing_indexes(db, &migration.indexes).await?;
+        let record = AppliedMigration {
+            version: migration.version,
+            name: migration.name.to_string(),
+            applied_at: Utc::now().to_rfc2822(),
+        };
+        db.cli
+            .database(&db.name)
+            .collection("Migration")
+            .insert_one(doc! {"version": record.version, "name": &record.name, "applied_at": &record.applied_at}, None)
+            .await?;
+        done.push(MigrationStatus { version: migration.version, name: migration.name, applied_at: Some(record.applied_at) });
+    }
+    Ok(done)
+}
+
+fn usage() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "usage: server_v2 migrate up [--to <version>] | server_v2 migrate status")
+}
+
+pub async fn run_command(db: &Database, args: &[String]) -> io::Result<()> {
+    let to_io = |e: Box<dyn Error>| io::Error::new(io::ErrorKind::Other, e.to_string());
+    let result = match args.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+        ["status"] => status(db).await.map_err(to_io)?,
+        ["up"] => up(db, None).await.map_err(to_io)?,
+        ["up", "--to", version] => up(db, Some(version.parse().map_err(|_| usage())?)).await.map_err(to_io)?,
+        _ => return Err(usage()),
+    };
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{TimeZone, Utc};
+    use mongodb::bson::oid::ObjectId;
+
+    use crate::util::migration::{migrations, object_id_time};
+
+    #[test]
+    fn test_migration_versions_increase() {
+        let versions = migrations().iter().map(|m| m.version).collect::<Vec<u32>>();
+        assert!(versions.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    // 已发布的迁移不能改动：版本 4 是 user-033 发布的非唯一查询索引
+    #[test]
+    fn test_lookup_indexes_unchanged() {
+        let migrations = migrations();
+        let v4 = migrations.iter().find(|m| m.version == 4).unwrap();
+        assert_eq!(v4.name, "lookup_indexes");
+        let names = v4.indexes.iter().map(|i| format!("{}.{}", i.collection, i.name())).collect::<Vec<String>>();
+        assert_eq!(names, vec!["User.username_1", "User.email_1", "Comment.cid_1", "Course.cid_1", "Detail.cid_1", "Rate.cid_1"]);
+        assert!(v4.indexes.iter().all(|i| !i.unique));
+    }
+
+    #[test]
+    fn test_object_id_time() {
+        let id = ObjectId::with_string("5f5b2c000000000000000000").unwrap();
+        assert_eq!(object_id_time(&id), Utc.timestamp(0x5f5b2c00, 0));
+    }
+}
@@ -4,5 +4,19 @@ pub mod database;
 pub mod macros;
 pub mod page_option;
 pub mod email_sender;
+pub mod email_template;
 pub mod crypto;
-pub mod ops;
\ No newline at end of file
+pub mod ops;
+pub mod backup;
+pub mod migration;
+pub mod index;
+pub mod logger;
+pub mod access_log;
+pub mod metrics;
+pub mod health;
+pub mod state;
+pub mod repository;
+pub mod memory_store;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod security;
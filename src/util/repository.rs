@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::future;
+use futures::stream::StreamExt;
+use mongodb::bson::{Bson, doc, Document, from_bson};
+use mongodb::options::{FindOneOptions, ReplaceOptions};
+use serde::de::DeserializeOwned;
+
+use crate::resources::comment::Comment;
+use crate::resources::course::Course;
+use crate::resources::detail::Detail;
+use crate::resources::rate::Rate;
+use crate::resources::user::User;
+use crate::util::database::Database;
+use crate::util::memory_store::MemoryStore;
+
+// 用户、评论和目录查询的处理器只通过这些 trait 访问集合，测试时换成 MemoryStore 就不需要 MongoDB。
+// 聚合查询（课程视图、院系、教师）、目录的增删改、导入、邀请码和注册验证码仍直接用 Database，
+// 它们依赖聚合管道或多集合写入，用 TestApp 测试
+#[async_trait(?Send)]
+pub trait UserRepository: Send + Sync {
+    async fn find_user(&self, filter: Document) -> Result<Option<User>, Box<dyn Error>>;
+    async fn count_users(&self, filter: Document) -> Result<i64, Box<dyn Error>>;
+    async fn update_user(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>>;
+    async fn delete_user(&self, filter: Document) -> Result<i64, Box<dyn Error>>;
+}
+
+#[async_trait(?Send)]
+pub trait CommentRepository: Send + Sync {
+    async fn find_comments(&self, filter: Document) -> Result<Vec<Comment>, Box<dyn Error>>;
+    // 不存在时插入，返回被替换或新插入评论的 _id
+    async fn replace_comment(&self, filter: Document, comment: Document) -> Result<Bson, Box<dyn Error>>;
+    async fn update_comment(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>>;
+    async fn delete_comment(&self, filter: Document) -> Result<i64, Box<dyn Error>>;
+    // 以下两个作用于所有匹配的评论
+    async fn update_comments(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>>;
+    async fn delete_comments(&self, filter: Document) -> Result<i64, Box<dyn Error>>;
+}
+
+// Course 中每个教学班一条记录，查询结果按 cid 合并
+#[async_trait(?Send)]
+pub trait CourseRepository: Send + Sync {
+    async fn find_courses(&self, filter: Document) -> Result<Vec<Course>, Box<dyn Error>>;
+}
+
+#[async_trait(?Send)]
+pub trait DetailRepository: Send + Sync {
+    async fn find_details(&self, filter: Document) -> Result<Vec<Detail>, Box<dyn Error>>;
+}
+
+#[async_trait(?Send)]
+pub trait RateRepository: Send + Sync {
+    async fn find_rates(&self, filter: Document) -> Result<Vec<Rate>, Box<dyn Error>>;
+}
+
+#[derive(Clone)]
+pub struct Repositories {
+    pub users: Arc<dyn UserRepository>,
+    pub comments: Arc<dyn CommentRepository>,
+    pub courses: Arc<dyn CourseRepository>,
+    pub details: Arc<dyn DetailRepository>,
+    pub rates: Arc<dyn RateRepository>,
+}
+
+impl Repositories {
+    pub fn mongo(db: &Database) -> Repositories {
+        let db = Arc::new(db.clone());
+        Repositories {
+            users: db.clone(),
+            comments: db.clone(),
+            courses: db.clone(),
+            details: db.clone(),
+            rates: db,
+        }
+    }
+
+    pub fn memory(store: Arc<MemoryStore>) -> Repositories {
+        Repositories {
+            users: store.clone(),
+            comments: store.clone(),
+            courses: store.clone(),
+            details: store.clone(),
+            rates: store,
+        }
+    }
+}
+
+// 无法反序列化的文档直接跳过，和原来各资源函数的行为一致
+async fn find_all<T: DeserializeOwned>(db: &Database, collection: &str, filter: Document) -> Result<Vec<T>, Box<dyn Error>> {
+    Ok(db
+        .cli
+        .database(&db.name)
+        .collection(collection)
+        .find(filter, None)
+        .await?
+        .filter_map(|d| future::ready(d.ok().and_then(|d| from_bson::<T>(Bson::Document(d)).ok())))
+        .collect::<Vec<T>>()
+        .await)
+}
+
+#[async_trait(?Send)]
+impl UserRepository for Database {
+    async fn find_user(&self, filter: Document) -> Result<Option<User>, Box<dyn Error>> {
+        match self.cli.database(&self.name).collection("User").find_one(filter, None).await? {
+            Some(user) => Ok(Some(from_bson::<User>(Bson::Document(user))?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn count_users(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.cli.database(&self.name).collection("User").count_documents(filter, None).await?)
+    }
+
+    async fn update_user(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.cli.database(&self.name).collection("User").update_one(filter, update, None).await?.modified_count)
+    }
+
+    async fn delete_user(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.cli.database(&self.name).collection("User").delete_one(filter, None).await?.deleted_count)
+    }
+}
+
+#[async_trait(?Send)]
+impl CommentRepository for Database {
+    async fn find_comments(&self, filter: Document) -> Result<Vec<Comment>, Box<dyn Error>> {
+        find_all(self, "Comment", filter).await
+    }
+
+    async fn replace_comment(&self, filter: Document, comment: Document) -> Result<Bson, Box<dyn Error>> {
+        let collection = self.cli.database(&self.name).collection("Comment");
+        let result = collection
+            .replace_one(filter.clone(), comment, ReplaceOptions::builder().upsert(true).build())
+            .await?;
+        if let Some(id) = result.upserted_id {
+            return Ok(id);
+        }
+        // 替换已有文档时没有 upserted_id，{cid, comment_by} 唯一，按原条件取回 _id
+        let existing = collection
+            .find_one(filter, FindOneOptions::builder().projection(doc! {"_id": 1}).build())
+            .await?
+            .ok_or("comment replaced but not found")?;
+        Ok(existing.get("_id").cloned().unwrap_or(Bson::Null))
+    }
+
+    async fn update_comment(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.cli.database(&self.name).collection("Comment").update_one(filter, update, None).await?.modified_count)
+    }
+
+    async fn delete_comment(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.cli.database(&self.name).collection("Comment").delete_one(filter, None).await?.deleted_count)
+    }
+
+    async fn update_comments(&self, filter: Document, update: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.cli.database(&self.name).collection("Comment").update_many(filter, update, None).await?.modified_count)
+    }
+
+    async fn delete_comments(&self, filter: Document) -> Result<i64, Box<dyn Error>> {
+        Ok(self.cli.database(&self.name).collection("Comment").delete_many(filter, None).await?.deleted_count)
+    }
+}
+
+#[async_trait(?Send)]
+impl CourseRepository for Database {
+    async fn find_courses(&self, filter: Document) -> Result<Vec<Course>, Box<dyn Error>> {
+        let aggregator = doc! {
+            "$group" : {
+                "_id" : "$cid",
+                "cid" : {"$first": "$cid"},
+                "name" : {"$first": "$name"},
+                "faculty" : {"$first": "$faculty"},
+                "taught_by" : {"$addToSet": "$taught_by"},
+            }
+        };
+        Ok(self
+            .cli
+            .database(&self.name)
+            .collection("Course")
+            .aggregate(vec![doc! {"$match": filter}, aggregator], None)
+            .await?
+            .filter_map(|d| future::ready(d.ok().and_then(|d| from_bson::<Course>(Bson::Document(d)).ok())))
+            .collect::<Vec<Course>>()
+            .await)
+    }
+}
+
+#[async_trait(?Send)]
+impl DetailRepository for Database {
+    async fn find_details(&self, filter: Document) -> Result<Vec<Detail>, Box<dyn Error>> {
+        find_all(self, "Detail", filter).await
+    }
+}
+
+#[async_trait(?Send)]
+impl RateRepository for Database {
+    async fn find_rates(&self, filter: Document) -> Result<Vec<Rate>, Box<dyn Error>> {
+        find_all(self, "Rate", filter).await
+    }
+}
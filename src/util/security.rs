@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{HttpResponse, web};
+use actix_web::dev::{HttpResponseBuilder, ServiceRequest, ServiceResponse};
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use futures::future::{LocalBoxFuture, ok, Ready};
+
+use crate::util::access_log::REQUEST_ID_HEADER;
+use crate::util::config::SecurityConfig;
+use crate::util::json_response::JsonResponse;
+
+const DEFAULT_METHODS: [&str; 5] = ["GET", "POST", "PUT", "PATCH", "DELETE"];
+const DEFAULT_HEADERS: [&str; 3] = ["Authorization", "Content-Type", "X-Request-Id"];
+const DEFAULT_MAX_AGE: u32 = 3600;
+const DEFAULT_JSON_LIMIT: usize = 32 * 1024;
+
+// 接口只返回 JSON，不需要被嵌入页面，也不需要加载任何资源
+const SECURITY_HEADERS: [(&str, &str); 4] = [
+    ("x-content-type-options", "nosniff"),
+    ("x-frame-options", "DENY"),
+    ("referrer-policy", "no-referrer"),
+    ("content-security-policy", "default-src 'none'; frame-ancestors 'none'"),
+];
+
+#[derive(Debug)]
+pub enum SecurityError {
+    WildcardWithCredentials,
+    InvalidOrigin(String),
+}
+
+impl fmt::Display for SecurityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SecurityError::WildcardWithCredentials => write!(f, "allowed_origins cannot contain \"*\" when allow_credentials is on"),
+            SecurityError::InvalidOrigin(origin) => write!(f, "invalid origin {}, expected scheme://host[:port]", origin),
+        }
+    }
+}
+
+impl Error for SecurityError {}
+
+#[derive(Debug, Clone)]
+pub struct SecurityPolicy {
+    any_origin: bool,
+    origins: Vec<String>,
+    methods: String,
+    headers: String,
+    credentials: bool,
+    max_age: u32,
+    hsts_max_age: u32,
+    json_limit: usize,
+    payload_limits: BTreeMap<String, usize>,
+}
+
+fn normalize_origin(origin: &str) -> String {
+    origin.trim().trim_end_matches('/').to_lowercase()
+}
+
+fn join(values: &Option<Vec<String>>, default: &[&str]) -> String {
+    match values {
+        Some(values) => values.join(", "),
+        None => default.join(", "),
+    }
+}
+
+impl SecurityPolicy {
+    pub fn new(config: &SecurityConfig) -> Result<SecurityPolicy, SecurityError> {
+        let origins = config.allowed_origins
+            .iter()
+            .flatten()
+            .map(|origin| normalize_origin(origin))
+            .collect::<Vec<String>>();
+        let any_origin = origins.iter().any(|origin| origin == "*");
+        let credentials = config.allow_credentials.unwrap_or(false);
+        if any_origin && credentials {
+            return Err(SecurityError::WildcardWithCredentials);
+        }
+        if let Some(origin) = origins.iter().find(|o| *o != "*" && !o.starts_with("http://") && !o.starts_with("https://")) {
+            return Err(SecurityError::InvalidOrigin(origin.clone()));
+        }
+        Ok(SecurityPolicy {
+            any_origin,
+            origins,
+            methods: join(&config.allowed_methods, &DEFAULT_METHODS),
+            headers: join(&config.allowed_headers, &DEFAULT_HEADERS),
+            credentials,
+            max_age: config.max_age.unwrap_or(DEFAULT_MAX_AGE),
+            hsts_max_age: config.hsts_max_age.unwrap_or(0),
+            json_limit: config.json_limit.unwrap_or(DEFAULT_JSON_LIMIT),
+            payload_limits: config.payload_limits.clone().unwrap_or_default(),
+        })
+    }
+
+    // 返回写回 Access-Control-Allow-Origin 的值，不允许的来源不返回任何 CORS 头
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.any_origin {
+            Some("*".to_string())
+        } else if self.origins.contains(&normalize_origin(origin)) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn payload_limit(&self, path: &str) -> Option<usize> {
+        self.payload_limits.get(path.trim_end_matches('/')).copied()
+    }
+
+    // JSON 解析失败时也返回 JsonResponse 格式，超出大小返回 413
+    pub fn json_config(&self) -> web::JsonConfig {
+        web::JsonConfig::default()
+            .limit(self.json_limit)
+            .error_handler(|err, _| {
+                let mut res = match &err {
+                    JsonPayloadError::Overflow => HttpResponse::PayloadTooLarge(),
+                    _ => HttpResponse::BadRequest(),
+                };
+                let body = JsonResponse::<()> { data: None, error: Some(err.to_string()), meta: None };
+                InternalError::from_response(err, res.json(body)).into()
+            })
+    }
+
+    fn apply(&self, headers: &mut HeaderMap, allow_origin: Option<&str>, preflight: bool) {
+        for (name, value) in &SECURITY_HEADERS {
+            let name = HeaderName::from_static(name);
+            if !headers.contains_key(&name) {
+                headers.insert(name, HeaderValue::from_static(value));
+            }
+        }
+        if self.hsts_max_age > 0 {
+            if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", self.hsts_max_age)) {
+                headers.insert(header::STRICT_TRANSPORT_SECURITY, value);
+            }
+        }
+        let origin = match allow_origin.and_then(|origin| HeaderValue::from_str(origin).ok()) {
+            Some(origin) => origin,
+            None => return,
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if !self.any_origin {
+            headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.credentials {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+        }
+        if preflight {
+            if let Ok(methods) = HeaderValue::from_str(&self.methods) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+            }
+            if let Ok(allowed) = HeaderValue::from_str(&self.headers) {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+            }
+            headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(self.max_age));
+        } else {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(REQUEST_ID_HEADER));
+        }
+    }
+}
+
+// CORS、安全响应头和按路径的请求体大小限制
+pub struct Security {
+    policy: Arc<SecurityPolicy>,
+}
+
+impl Security {
+    pub fn new(policy: Arc<SecurityPolicy>) -> Security {
+        Security { policy }
+    }
+}
+
+impl<S, B> Transform<S> for Security
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=actix_web::Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = SecurityMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SecurityMiddleware { service, policy: self.policy.clone() })
+    }
+}
+
+pub struct SecurityMiddleware<S> {
+    service: S,
+    policy: Arc<SecurityPolicy>,
+}
+
+fn reject(policy: &SecurityPolicy, mut res: HttpResponseBuilder, allow_origin: Option<&str>, message: String) -> HttpResponse {
+    let body = JsonResponse::<()> { data: None, error: Some(message), meta: None };
+    let mut res = res.json(body);
+    policy.apply(res.headers_mut(), allow_origin, false);
+    res
+}
+
+impl<S, B> Service for SecurityMiddleware<S>
+    where
+        S: Service<Request=ServiceRequest, Response=ServiceResponse<B>, Error=actix_web::Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let policy = self.policy.clone();
+        let allow_origin = req.headers()
+            .get(header::ORIGIN)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|origin| policy.allow_origin(origin));
+
+        // 预检请求直接应答，不进入路由
+        if req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD) {
+            let mut res = match allow_origin {
+                Some(_) => HttpResponse::NoContent().finish(),
+                None => HttpResponse::Forbidden().finish(),
+            };
+            policy.apply(res.headers_mut(), allow_origin.as_deref(), true);
+            return Box::pin(ok(req.into_response(res.into_body())));
+        }
+
+        // 受限路径要求声明 Content-Length，分块上传无法在读取前判断大小
+        if let Some(limit) = policy.payload_limit(req.path()) {
+            let length = req.headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<usize>().ok());
+            let rejected = match length {
+                Some(length) if length > limit => Some(reject(&policy, HttpResponse::PayloadTooLarge(), allow_origin.as_deref(), format!("request body exceeds {} bytes", limit))),
+                None if req.headers().contains_key(header::TRANSFER_ENCODING) => Some(reject(&policy, HttpResponse::LengthRequired(), allow_origin.as_deref(), "Content-Length is required".to_string())),
+                _ => None,
+            };
+            if let Some(res) = rejected {
+                return Box::pin(ok(req.into_response(res.into_body())));
+            }
+        }
+
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let mut res = future.await?;
+            policy.apply(res.headers_mut(), allow_origin.as_deref(), false);
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use actix_web::{App, HttpResponse, test, web};
+    use actix_web::http::{header, Method, StatusCode};
+
+    use crate::util::config::SecurityConfig;
+    use crate::util::security::{Security, SecurityPolicy};
+
+    fn config(origins: &[&str], credentials: bool) -> SecurityConfig {
+        let mut limits = std::collections::BTreeMap::new();
+        limits.insert("/comment".to_string(), 16);
+        SecurityConfig {
+            allowed_origins: Some(origins.iter().map(|o| o.to_string()).collect()),
+            allow_credentials: Some(credentials),
+            payload_limits: Some(limits),
+            ..SecurityConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_policy_rejects_wildcard_with_credentials() {
+        assert!(SecurityPolicy::new(&config(&["*"], true)).is_err());
+        assert!(SecurityPolicy::new(&config(&["sustechflow.top"], false)).is_err());
+        assert!(SecurityPolicy::new(&config(&["https://sustechflow.top/"], true)).is_ok());
+    }
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_rt::test]
+    async fn test_security_middleware() {
+        let policy = Arc::new(SecurityPolicy::new(&config(&["https://sustechflow.top"], true)).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Security::new(policy))
+                .route("/comment", web::post().to(ok))
+        ).await;
+
+        let req = test::TestRequest::with_uri("/comment")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, "https://sustechflow.top")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://sustechflow.top");
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+
+        let req = test::TestRequest::with_uri("/comment")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, "https://evil.example")
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        let req = test::TestRequest::post()
+            .uri("/comment")
+            .header(header::ORIGIN, "https://sustechflow.top")
+            .header(header::CONTENT_LENGTH, "10")
+            .set_payload("0123456789")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-content-type-options").unwrap(), "nosniff");
+        assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://sustechflow.top");
+
+        let req = test::TestRequest::post()
+            .uri("/comment")
+            .header(header::CONTENT_LENGTH, "20")
+            .set_payload("0123456789abcdefghij")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
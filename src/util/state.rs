@@ -0,0 +1,109 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use mongodb::Client;
+
+use crate::resources::register_link::RegisterPolicy;
+use crate::util::config::{AccountConfig, CommentConfig, DatabaseConfig, EmailSenderConfig, HealthConfig, new, optional, RegisterConfig, SecurityConfig, TransportKind};
+use crate::util::database::Database;
+use crate::util::email_sender::EmailSender;
+use crate::util::index::IndexReport;
+use crate::util::memory_store::MemoryStore;
+use crate::util::repository::Repositories;
+use crate::util::security::SecurityPolicy;
+
+pub const DATABASE_CONFIG: &str = "config/Database.toml";
+const EMAIL_SENDER_CONFIG: &str = "config/EmailSender.toml";
+const REGISTER_CONFIG: &str = "config/Register.toml";
+const COMMENT_CONFIG: &str = "config/Comment.toml";
+const HEALTH_CONFIG: &str = "config/Health.toml";
+const SECURITY_CONFIG: &str = "config/Security.toml";
+const ACCOUNT_CONFIG: &str = "config/Account.toml";
+
+// 可选配置文件缺失时用默认值，写错了则启动失败，不会悄悄退回默认值
+pub struct Policies {
+    pub register: RegisterPolicy,
+    pub comment: CommentConfig,
+    pub health: HealthConfig,
+    pub security: Arc<SecurityPolicy>,
+    pub account: AccountConfig,
+}
+
+impl Policies {
+    pub async fn load() -> Result<Policies, Box<dyn Error>> {
+        let register = optional::<RegisterConfig>(REGISTER_CONFIG)
+            .await
+            .map_err(|e| format!("cannot load {}: {}", REGISTER_CONFIG, e))?;
+        let security = optional::<SecurityConfig>(SECURITY_CONFIG)
+            .await
+            .map_err(|e| format!("cannot load {}: {}", SECURITY_CONFIG, e))?;
+        Ok(Policies {
+            register: RegisterPolicy::new(&register)
+                .map_err(|e| format!("invalid local_part in {}: {}", REGISTER_CONFIG, e))?,
+            comment: optional(COMMENT_CONFIG)
+                .await
+                .map_err(|e| format!("cannot load {}: {}", COMMENT_CONFIG, e))?,
+            health: optional(HEALTH_CONFIG)
+                .await
+                .map_err(|e| format!("cannot load {}: {}", HEALTH_CONFIG, e))?,
+            security: Arc::new(SecurityPolicy::new(&security).map_err(|e| format!("invalid {}: {}", SECURITY_CONFIG, e))?),
+            account: optional(ACCOUNT_CONFIG)
+                .await
+                .map_err(|e| format!("cannot load {}: {}", ACCOUNT_CONFIG, e))?,
+        })
+    }
+}
+
+// 服务运行时需要的连接和配置在启动时一次性建立，失败直接退出，而不是等到第一个请求时 panic
+pub struct AppState {
+    pub db: Database,
+    pub repos: Repositories,
+    pub email_sender: EmailSender,
+    pub email_config: EmailSenderConfig,
+    pub policies: Policies,
+}
+
+// 子命令只需要数据库，不需要邮件和其他配置
+pub async fn connect_database() -> Result<Database, Box<dyn Error>> {
+    let database_config = new::<DatabaseConfig>(DATABASE_CONFIG)
+        .await
+        .map_err(|e| format!("cannot load {}: {}", DATABASE_CONFIG, e))?;
+    Ok(Database::new(&database_config)
+        .await
+        .map_err(|e| format!("cannot connect to MongoDB: {}", e))?)
+}
+
+impl AppState {
+    pub async fn new() -> Result<AppState, Box<dyn Error>> {
+        let email_config = new::<EmailSenderConfig>(EMAIL_SENDER_CONFIG)
+            .await
+            .map_err(|e| format!("cannot load {}: {}", EMAIL_SENDER_CONFIG, e))?;
+        let policies = Policies::load().await?;
+        let db = connect_database().await?;
+        AppState::with_database(db, email_config, policies).await
+    }
+
+    pub async fn with_database(db: Database, email_config: EmailSenderConfig, policies: Policies) -> Result<AppState, Box<dyn Error>> {
+        let email_sender = EmailSender::new(&email_config)
+            .await
+            .map_err(|e| format!("invalid email sender config: {}", e))?;
+        let repos = Repositories::mongo(&db);
+        Ok(AppState { db, repos, email_sender, email_config, policies })
+    }
+
+    // 测试用：集合都在内存里，邮件进内存发件箱；db 是不会被 ping 的惰性连接，
+    // 只有 Repositories 说明里列出的、仍直接用 Database 的处理器才会用到它
+    pub async fn memory(store: Arc<MemoryStore>) -> Result<AppState, Box<dyn Error>> {
+        let db = Database {
+            name: "memory".to_string(),
+            ip: "127.0.0.1".to_string(),
+            port: 27017,
+            cli: Client::with_uri_str("mongodb://127.0.0.1:27017").await?,
+            index_report: IndexReport::default(),
+        };
+        let email_config = EmailSenderConfig { transport: Some(TransportKind::Memory), ..EmailSenderConfig::default() };
+        let email_sender = EmailSender::new(&email_config).await?;
+        let policies = Policies::load().await?;
+        Ok(AppState { db, repos: Repositories::memory(store), email_sender, email_config, policies })
+    }
+}
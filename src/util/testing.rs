@@ -0,0 +1,110 @@
+use std::error::Error;
+
+use actix_web::web;
+use async_std::task::block_on;
+use lazy_static::lazy_static;
+use mongodb::bson::{doc, Document};
+use regex::Regex;
+use uuid::Uuid;
+
+use crate::resources::email_queue::process_due;
+use crate::util::config::{DatabaseConfig, EmailSenderConfig, new, TransportKind};
+use crate::util::database::Database;
+use crate::util::email_sender::Email;
+use crate::util::state::{AppState, DATABASE_CONFIG, Policies};
+
+lazy_static! {
+    static ref VCODE: Regex = Regex::new(r"vcode=([0-9A-Za-z-]+)").unwrap();
+}
+
+fn comment(cid: &str, comment_by: &str, taught: &[&str], likes: f64, useful: f64, easy: f64) -> Document {
+    doc! {
+        "gpa": "A", "cid": cid, "content": "good", "comment_by": comment_by, "term": "秋", "willing": true, "anonymous": false,
+        "rate": {"likes": likes, "useful": useful, "easy": easy, "ratings": 4.0}, "taught": taught,
+        "helpful": 0, "not_helpful": 0, "year": 2020, "month": 1, "day": 1,
+    }
+}
+
+// 测试用的服务状态：每个实例使用独立命名的数据库，drop 时删除；邮件只进内存发件箱
+pub struct TestApp {
+    pub state: web::Data<AppState>,
+}
+
+impl TestApp {
+    pub async fn new() -> Result<TestApp, Box<dyn Error>> {
+        let mut database_config = new::<DatabaseConfig>(DATABASE_CONFIG).await?;
+        database_config.name = Some(format!("flow_test_{}", Uuid::new_v4().to_simple()));
+        let db = Database::new(&database_config).await?;
+        let email_config = EmailSenderConfig { transport: Some(TransportKind::Memory), ..EmailSenderConfig::default() };
+        Ok(TestApp { state: web::Data::new(AppState::with_database(db, email_config, Policies::load().await?).await?) })
+    }
+
+    pub fn db(&self) -> &Database {
+        &self.state.db
+    }
+
+    // 目录相关测试共用的数据：计算机系的 CS101 有两个教学班、Detail、Rate 和两条评论，
+    // CS102 只有一个教学班和一条评论（老师名字带空格）；数学系的 MA101 只有 Rate
+    pub async fn seed_catalog(&self) -> Result<(), Box<dyn Error>> {
+        let database = self.db().cli.database(&self.db().name);
+        database
+            .collection("Course")
+            .insert_many(vec![
+                doc! {"cid": "CS101", "name": "计算机导论", "faculty": "计算机系", "taught_by": ["Alice"]},
+                doc! {"cid": "CS101", "name": "计算机导论", "faculty": "计算机系", "taught_by": ["Bob"]},
+                doc! {"cid": "CS102", "name": "程序设计", "faculty": "计算机系", "taught_by": ["Alice"]},
+                doc! {"cid": "MA101", "name": "数学分析", "faculty": "数学系", "taught_by": ["Carol"]},
+            ], None)
+            .await?;
+        database
+            .collection("Detail")
+            .insert_one(doc! {
+                "cid": "CS101", "name": "计算机导论", "english_name": "Intro to CS",
+                "open_by": "计算机系", "credit": "3", "detail": "",
+            }, None)
+            .await?;
+        database
+            .collection("Rate")
+            .insert_many(vec![
+                doc! {"cid": "CS101", "name": "计算机导论", "ratings": 4.0, "likes": 5.0, "useful": 4.0, "easy": 3.0},
+                doc! {"cid": "MA101", "name": "数学分析", "ratings": 3.0, "likes": 3.0, "useful": 5.0, "easy": 1.0},
+            ], None)
+            .await?;
+        database
+            .collection("Comment")
+            .insert_many(vec![
+                comment("CS101", "a", &["Alice"], 5.0, 4.0, 3.0),
+                comment("CS101", "b", &["Bob", "Alice"], 3.0, 2.0, 1.0),
+                comment("CS102", "a", &["Alice "], 4.0, 3.0, 2.0),
+            ], None)
+            .await?;
+        Ok(())
+    }
+
+    // 先投递队列里到期的邮件，再返回发件箱
+    pub async fn sent(&self) -> Result<Vec<Email>, Box<dyn Error>> {
+        process_due(&self.state.db, &self.state.email_sender).await?;
+        Ok(self.state.email_sender.sent())
+    }
+
+    // 最后一封发给 recv 的邮件中的验证码
+    pub async fn last_code(&self, recv: &str) -> Result<String, Box<dyn Error>> {
+        Ok(self
+            .sent()
+            .await?
+            .iter()
+            .rev()
+            .filter(|email| email.to.eq_ignore_ascii_case(recv))
+            .find_map(|email| VCODE.captures(&email.body).map(|c| c[1].to_string()))
+            .ok_or("no code sent")?)
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let db = &self.state.db;
+        if let Err(err) = block_on(db.cli.database(&db.name).drop(None)) {
+            log::warn!("drop test database {} failed: {}", db.name, err);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+extern crate server_v2;
+
+
+mod routes_test {
+    use actix_web::{App, test};
+    use mongodb::bson::doc;
+    use rand::Rng;
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use server_v2::resources;
+    use server_v2::util::testing::TestApp;
+
+    macro_rules! init_service {
+        ($app: expr) => {
+            test::init_service(App::new().app_data($app.state.clone()).configure(resources::config)).await
+        };
+    }
+
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", token)
+    }
+
+    // 注册一个账号并返回 (用户名, 登录 token)；admin 为 true 时直接在库里提权
+    macro_rules! register {
+        ($app: expr, $srv: expr, $admin: expr) => {{
+            let username = Uuid::new_v4().to_simple().to_string()[..16].to_string();
+            let email = format!("{}@mail.sustech.edu.cn", rand::thread_rng().gen_range(1000_0000, 9999_9999) as u32);
+            let req = test::TestRequest::get().uri(&format!("/register_link?email={}", email)).to_request();
+            let res: Value = test::read_response_json(&mut $srv, req).await;
+            assert!(res["error"].is_null(), "{}", res);
+            let code = $app.last_code(&email).await.unwrap();
+            let req = test::TestRequest::post()
+                .uri("/user")
+                .set_json(&json!({"username": username, "password": "test", "email": email, "vcode": code}))
+                .to_request();
+            let res: Value = test::read_response_json(&mut $srv, req).await;
+            assert!(res["error"].is_null(), "{}", res);
+            if $admin {
+                $app.db()
+                    .cli
+                    .database(&$app.db().name)
+                    .collection("User")
+                    .update_one(doc! {"username": &username}, doc! {"$set": {"admin": true}}, None)
+                    .await
+                    .unwrap();
+            }
+            (username, res["data"]["token"].as_str().unwrap().to_string())
+        }};
+    }
+
+    macro_rules! get {
+        ($srv: expr, $uri: expr) => {{
+            let req = test::TestRequest::get().uri($uri).to_request();
+            let res: Value = test::read_response_json(&mut $srv, req).await;
+            res
+        }};
+        ($srv: expr, $uri: expr, $token: expr) => {{
+            let req = test::TestRequest::get().uri($uri).header("Authorization", bearer($token)).to_request();
+            let res: Value = test::read_response_json(&mut $srv, req).await;
+            res
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn test_catalog_routes() {
+        let app = TestApp::new().await.unwrap();
+        let mut srv = init_service!(app);
+        app.seed_catalog().await.unwrap();
+
+        let res = get!(srv, "/course");
+        assert_eq!(res["data"].as_array().unwrap().len(), 3, "{}", res);
+        let res = get!(srv, "/course?cid=CS101");
+        assert_eq!(res["data"][0]["taught_by"].as_array().unwrap().len(), 2, "{}", res);
+
+        let res = get!(srv, "/course/CS101");
+        assert_eq!(res["data"]["detail"]["credit"], json!("3"), "{}", res);
+        assert_eq!(res["data"]["rate"]["likes"], json!(5.0));
+        assert_eq!(res["data"]["comment_count"], json!(2));
+        let res = get!(srv, "/course/CS102");
+        assert_eq!(res["data"]["name"], json!("程序设计"), "{}", res);
+        assert!(res["data"]["detail"].is_null());
+        assert!(res["data"]["rate"].is_null());
+        let res = get!(srv, "/course/CS999");
+        assert!(res["data"].is_null());
+        assert!(!res["error"].is_null());
+
+        let res = get!(srv, "/rate?cid=CS101");
+        assert_eq!(res["data"].as_array().unwrap().len(), 1, "{}", res);
+        assert_eq!(res["data"][0]["easy"], json!(3.0));
+        let res = get!(srv, "/detail?cid=CS101");
+        assert_eq!(res["data"][0]["english_name"], json!("Intro to CS"), "{}", res);
+        let res = get!(srv, "/detail?cid=CS102");
+        assert_eq!(res["data"], json!([]), "{}", res);
+
+        let res = get!(srv, "/teacher?name=Alice");
+        assert_eq!(res["data"][0]["name"], json!("Alice"), "{}", res);
+        assert_eq!(res["data"][0]["courses"].as_array().unwrap().len(), 2);
+
+        let res = get!(srv, "/faculty");
+        assert_eq!(res["data"].as_array().unwrap().len(), 2, "{}", res);
+        let cs = res["data"].as_array().unwrap().iter().find(|f| f["faculty"] == json!("计算机系")).unwrap();
+        assert_eq!(cs["courses"], json!(2));
+    }
+
+    #[actix_rt::test]
+    async fn test_admin_routes() {
+        let app = TestApp::new().await.unwrap();
+        let mut srv = init_service!(app);
+        let (_, user_token) = register!(app, srv, false);
+        let (_, admin_token) = register!(app, srv, true);
+
+        for uri in &["/admin/invite", "/admin/email_queue"] {
+            let res = get!(srv, uri, &user_token);
+            assert!(res["data"].is_null(), "{}", res);
+            let res = get!(srv, uri, &admin_token);
+            assert!(res["error"].is_null(), "{}", res);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/course")
+            .header("Authorization", bearer(&user_token))
+            .set_json(&json!({"cid": "CS201", "name": "数据结构", "faculty": "计算机系", "taught_by": [["Alice"]]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["data"].is_null());
+        let req = test::TestRequest::post()
+            .uri("/course")
+            .header("Authorization", bearer(&admin_token))
+            .set_json(&json!({"cid": "CS201", "name": "数据结构", "faculty": "计算机系", "taught_by": [["Alice"]]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!(1), "{}", res);
+
+        let req = test::TestRequest::post()
+            .uri("/detail")
+            .header("Authorization", bearer(&admin_token))
+            .set_json(&json!({"cid": "CS201", "name": "数据结构", "english_name": "", "open_by": "计算机系", "credit": "3", "detail": ""}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["error"].is_null(), "{}", res);
+
+        let req = test::TestRequest::put()
+            .uri("/course/CS201")
+            .header("Authorization", bearer(&admin_token))
+            .set_json(&json!({"cid": "CS201", "name": "数据结构", "faculty": "计算机系", "taught_by": [["Alice"], ["Bob"]]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!(2), "{}", res);
+
+        let req = test::TestRequest::patch()
+            .uri("/course/CS201")
+            .header("Authorization", bearer(&admin_token))
+            .set_json(&json!({"Set": ["name", "数据结构与算法"]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["error"].is_null(), "{}", res);
+        let res = get!(srv, "/course/CS201");
+        assert_eq!(res["data"]["name"], json!("数据结构与算法"), "{}", res);
+        assert_eq!(res["data"]["detail"]["name"], json!("数据结构与算法"));
+
+        let req = test::TestRequest::patch()
+            .uri("/detail/CS201")
+            .header("Authorization", bearer(&admin_token))
+            .set_json(&json!({"Set": ["credit", "three"]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["data"].is_null());
+
+        let req = test::TestRequest::delete()
+            .uri("/detail/CS201")
+            .header("Authorization", bearer(&admin_token))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!(1), "{}", res);
+        let req = test::TestRequest::delete()
+            .uri("/course/CS201")
+            .header("Authorization", bearer(&admin_token))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!(2), "{}", res);
+        let res = get!(srv, "/course/CS201");
+        assert!(res["data"].is_null());
+
+        let csv = "cid,name,english_name,faculty,credit,detail,instructors\n\
+                   MA101,数学分析,,数学系,5,,张三\n\
+                   MA101,数学分析,,数学系,5,,李四\n";
+        let req = test::TestRequest::post()
+            .uri("/admin/import?format=csv&dry_run=true")
+            .header("Authorization", bearer(&admin_token))
+            .set_payload(csv)
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["added"], json!(["MA101"]), "{}", res);
+        assert_eq!(res["data"]["written"], json!(0));
+        let req = test::TestRequest::post()
+            .uri("/admin/import?format=csv")
+            .header("Authorization", bearer(&admin_token))
+            .set_payload(csv)
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["written"], json!(1), "{}", res);
+        let res = get!(srv, "/course?cid=MA101");
+        assert_eq!(res["data"][0]["taught_by"].as_array().unwrap().len(), 2, "{}", res);
+
+        let req = test::TestRequest::post()
+            .uri("/admin/invite")
+            .header("Authorization", bearer(&admin_token))
+            .set_json(&json!({"max_uses": 2, "expire_days": 7}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        let code = res["data"]["code"].clone();
+        assert!(code.is_string(), "{}", res);
+        let res = get!(srv, "/admin/invite", &admin_token);
+        assert_eq!(res["data"][0]["code"], code, "{}", res);
+        assert_eq!(res["data"][0]["max_uses"], json!(2));
+
+        // 两次注册各发出一封验证码邮件
+        let res = get!(srv, "/admin/email_queue", &admin_token);
+        assert_eq!(res["data"]["sent"], json!(2), "{}", res);
+        assert_eq!(res["data"]["dead"], json!(0));
+    }
+}
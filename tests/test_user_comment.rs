@@ -2,49 +2,220 @@ extern crate server_v2;
 
 
 mod user_comment_test {
-    use futures_await_test::async_test;
+    use actix_web::{App, test};
     use mongodb::bson::doc;
     use rand::Rng;
+    use serde_json::{json, Value};
     use uuid::Uuid;
 
-    use server_v2::resources::register_link::get_register_link;
-    use server_v2::resources::session::{AuthInfo, post_session, Session};
-    use server_v2::resources::session::SESSION_POOL;
-    use server_v2::resources::user::{post_user, RegisterInfo};
-    use server_v2::util::database::DEFAULT_DATABASE;
+    use server_v2::resources;
+    use server_v2::util::testing::TestApp;
 
-    async fn create_user() -> AuthInfo {
-        let username = Uuid::new_v4().to_string();
+    macro_rules! init_service {
+        ($app: expr) => {
+            test::init_service(App::new().app_data($app.state.clone()).configure(resources::config)).await
+        };
+    }
+
+    fn new_account() -> (String, String) {
+        let username = Uuid::new_v4().to_simple().to_string()[..16].to_string();
         let mut rng = rand::thread_rng();
-        let email = (rng.gen_range(1000_0000, 9999_9999) as u32).to_string();
-        let code = get_register_link(&(email.clone() + "@sustech.edu.cn")).await.unwrap().code;
+        let email = format!("{}@mail.sustech.edu.cn", rng.gen_range(1000_0000, 9999_9999) as u32);
+        (username, email)
+    }
 
-        assert!(post_user(None, RegisterInfo{username:username.clone(), password: "test".to_string(), vcode: code, email}).await.is_ok());
-        AuthInfo {
-            username,
-            password: "test".to_string(),
-        }
+    fn bearer(token: &str) -> String {
+        format!("Bearer {}", token)
     }
 
-    async fn delete_user(username: &str) {
-        let db = &DEFAULT_DATABASE;
-        assert!(db.cli.database(&db.name).collection("User")
-            .delete_one(doc! {"username": username}, None).await.is_ok());
+    // 走一遍 /register_link 和 /user，返回 (用户名, 登录 token)
+    macro_rules! register {
+        ($app: expr, $srv: expr) => {{
+            let (username, email) = new_account();
+            let req = test::TestRequest::get().uri(&format!("/register_link?email={}", email)).to_request();
+            let res: Value = test::read_response_json(&mut $srv, req).await;
+            assert!(res["error"].is_null(), "{}", res);
+            assert!(res["data"]["code"].is_null());
+            let code = $app.last_code(&email).await.unwrap();
+            let req = test::TestRequest::post()
+                .uri("/user")
+                .set_json(&json!({"username": username, "password": "test", "email": email, "vcode": code}))
+                .to_request();
+            let res: Value = test::read_response_json(&mut $srv, req).await;
+            assert!(res["error"].is_null(), "{}", res);
+            (username, res["data"]["token"].as_str().unwrap().to_string())
+        }};
     }
 
-    async fn login(auth: AuthInfo) -> Session {
-        let res = post_session(auth).await;
-        assert!(res.is_ok());
-        res.unwrap()
+    #[actix_rt::test]
+    async fn test_register_rejects_wrong_code() {
+        let app = TestApp::new().await.unwrap();
+        let mut srv = init_service!(app);
+        let (username, email) = new_account();
+        let req = test::TestRequest::get().uri(&format!("/register_link?email={}", email)).to_request();
+        let _: Value = test::read_response_json(&mut srv, req).await;
+        let req = test::TestRequest::post()
+            .uri("/user")
+            .set_json(&json!({"username": username, "password": "test", "email": email, "vcode": "wrong"}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["data"].is_null());
+        assert!(!res["error"].is_null());
     }
 
-    #[async_test]
+    #[actix_rt::test]
     async fn test_post_user_delete_user() {
-        let auth = create_user().await;
-        let username = auth.username.clone();
-        let s1 = login(auth).await;
-        let s2 = SESSION_POOL.lock().unwrap().get(&s1.token).unwrap().clone();
-        assert_eq!(s1, s2);
-        delete_user(&username).await;
-    }
-}
\ No newline at end of file
+        let app = TestApp::new().await.unwrap();
+        let mut srv = init_service!(app);
+        let (username, token) = register!(app, srv);
+
+        let req = test::TestRequest::get().uri(&format!("/user/available?username={}", username)).to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["available"], json!(false));
+
+        let req = test::TestRequest::patch()
+            .uri("/user")
+            .header("Authorization", bearer(&token))
+            .set_json(&json!({"AddToSet": ["learnt_course", "CS101"]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!(1));
+
+        let req = test::TestRequest::get().uri("/user").header("Authorization", bearer(&token)).to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["username"], json!(username));
+        assert_eq!(res["data"]["learnt_course"], json!(["CS101"]));
+
+        let req = test::TestRequest::get().uri("/user/export").header("Authorization", bearer(&token)).to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["profile"]["username"], json!(username));
+        assert!(res["data"]["profile"].get("permanent_token").is_none());
+
+        let req = test::TestRequest::delete()
+            .uri("/user")
+            .header("Authorization", bearer(&token))
+            .set_json(&json!({"password": "test"}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["sessions_revoked"], json!(1));
+
+        let req = test::TestRequest::post()
+            .uri("/session")
+            .set_json(&json!({"username": username, "password": "test"}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["data"].is_null());
+    }
+
+    #[actix_rt::test]
+    async fn test_session() {
+        let app = TestApp::new().await.unwrap();
+        let mut srv = init_service!(app);
+        let (username, _) = register!(app, srv);
+
+        let req = test::TestRequest::post()
+            .uri("/session")
+            .set_json(&json!({"username": username, "password": "wrong"}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["data"].is_null());
+
+        let req = test::TestRequest::post()
+            .uri("/session")
+            .set_json(&json!({"username": username, "password": "test"}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        let token = res["data"]["token"].as_str().unwrap().to_string();
+
+        let req = test::TestRequest::get().uri("/session").header("Authorization", bearer(&token)).to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["username"], json!(username));
+
+        let req = test::TestRequest::delete().uri("/session").header("Authorization", bearer(&token)).to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"]["token"], json!(token));
+
+        let req = test::TestRequest::get().uri("/session").header("Authorization", bearer(&token)).to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["data"].is_null());
+    }
+
+    #[actix_rt::test]
+    async fn test_comment() {
+        let app = TestApp::new().await.unwrap();
+        let mut srv = init_service!(app);
+        let (username, token) = register!(app, srv);
+        app.db()
+            .cli
+            .database(&app.db().name)
+            .collection("Course")
+            .insert_one(doc! {"cid": "CS101", "name": "计算机导论", "faculty": "计算机系", "taught_by": ["Alice"]}, None)
+            .await
+            .unwrap();
+        let comment = json!({
+            "gpa": "A", "cid": "CS101", "content": "good", "term": "秋", "willing": true, "anonymous": false,
+            "rate": {"likes": 5.0, "useful": 4.0, "easy": 3.0, "ratings": 4.0}, "taught": ["Alice"],
+            "helpful": null, "not_helpful": null, "year": 2020, "month": 1, "day": 1
+        });
+
+        let req = test::TestRequest::post()
+            .uri("/comment")
+            .header("Authorization", bearer(&token))
+            .set_json(&comment)
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["error"].is_null(), "{}", res);
+        let id = res["data"].clone();
+
+        let req = test::TestRequest::get().uri("/comment?cid=CS101").to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        let created_at = res["data"][0]["created_at"].clone();
+        assert!(!created_at.is_null());
+
+        // 重新提交返回同一个 _id，创建时间不变
+        let req = test::TestRequest::post()
+            .uri("/comment")
+            .header("Authorization", bearer(&token))
+            .set_json(&comment)
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], id, "{}", res);
+        let req = test::TestRequest::get().uri("/comment?cid=CS101").to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"].as_array().unwrap().len(), 1);
+        assert_eq!(res["data"][0]["created_at"], created_at);
+
+        let req = test::TestRequest::patch()
+            .uri("/comment?cid=CS101")
+            .header("Authorization", bearer(&token))
+            .set_json(&json!({"Set": ["year", 2030]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert!(res["data"].is_null());
+
+        let req = test::TestRequest::patch()
+            .uri("/comment?cid=CS101")
+            .header("Authorization", bearer(&token))
+            .set_json(&json!({"Set": ["content", "<b>better</b>"]}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!(1));
+
+        let req = test::TestRequest::get().uri("/comment?cid=CS101").to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"][0]["content"], json!("better"));
+        assert_eq!(res["data"][0]["comment_by"], json!(username));
+
+        let req = test::TestRequest::delete()
+            .uri("/comment")
+            .header("Authorization", bearer(&token))
+            .set_json(&json!({"cid": "CS101"}))
+            .to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!(1));
+
+        let req = test::TestRequest::get().uri("/comment?cid=CS101").to_request();
+        let res: Value = test::read_response_json(&mut srv, req).await;
+        assert_eq!(res["data"], json!([]));
+    }
+}